@@ -0,0 +1,86 @@
+use crate::db::{Station, StationGeometry};
+use anyhow::{Context, Result, bail};
+use deadpool_postgres::GenericClient;
+use sea_query::{Expr, Iden, SimpleExpr};
+use std::collections::HashSet;
+
+/// WGS 84, the coordinates of the NS API.
+pub const SRID: i32 = 4326;
+
+/// Checks that the geometry columns exist, like `check-schema --postgis` does. They are added by the
+/// persister seeds when `DB_POSTGIS` is set, next to `station.location` and
+/// `station_geometry.line_string`, which the API still reads. So PostGIS stays optional for anyone
+/// running without it.
+pub(crate) async fn check_columns(client: &impl GenericClient) -> Result<()> {
+    let rows = client
+        .query(
+            "SELECT table_name::text, column_name::text
+            FROM information_schema.columns
+            WHERE table_schema = current_schema() AND udt_name = 'geometry'",
+            &[],
+        )
+        .await
+        .context("! failed to check for PostGIS columns")?;
+    let found = rows
+        .iter()
+        .map(|row| (row.get::<_, String>(0), row.get::<_, String>(1)))
+        .collect::<HashSet<_>>();
+
+    let missing = [
+        (
+            Station::Table.to_string(),
+            Station::LocationGeometry.to_string(),
+        ),
+        (
+            StationGeometry::Table.to_string(),
+            StationGeometry::LineStringGeometry.to_string(),
+        ),
+    ]
+    .into_iter()
+    .filter(|column| !found.contains(column))
+    .map(|(table, column)| format!("{table}.{column}"))
+    .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        bail!(
+            "! PostGIS columns {} are missing, run the persister seeds with DB_POSTGIS=true",
+            missing.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// A point in x/y order, so longitude first, unlike the `point(lat, lng)` in `station.location`.
+pub(crate) fn point(lat: f64, lng: f64) -> SimpleExpr {
+    Expr::cust(format!("ST_SetSRID(ST_MakePoint({lng}, {lat}), {SRID})"))
+}
+
+/// A geometry from a GeoJSON geometry object, which is always in longitude/latitude order.
+pub(crate) fn from_geojson(geojson: String) -> SimpleExpr {
+    Expr::cust_with_values(
+        format!("ST_SetSRID(ST_GeomFromGeoJSON($1), {SRID})"),
+        [geojson],
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sea_query::{PostgresQueryBuilder, Query};
+
+    #[test]
+    fn it_builds_geometries_in_lng_lat_order() {
+        let sql = Query::select()
+            .expr(point(52.0894, 5.1101))
+            .expr(from_geojson(
+                r#"{"type":"LineString","coordinates":[[5.1101,52.0894]]}"#.to_string(),
+            ))
+            .to_string(PostgresQueryBuilder);
+
+        assert_eq!(
+            sql,
+            r#"SELECT ST_SetSRID(ST_MakePoint(5.1101, 52.0894), 4326), ST_SetSRID(ST_GeomFromGeoJSON(E'{\"type\":\"LineString\",\"coordinates\":[[5.1101,52.0894]]}'), 4326)"#
+        );
+    }
+}
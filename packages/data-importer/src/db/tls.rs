@@ -0,0 +1,142 @@
+use anyhow::{Context, Result, anyhow};
+use clap::ValueEnum;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+/// How the connection to the database is secured, named after the `sslmode` values of libpq.
+#[derive(Debug, Default, Clone, Copy, PartialEq, ValueEnum)]
+pub enum SslMode {
+    /// Never use TLS
+    Disable,
+    /// Use TLS if the server supports it, without verifying its certificate. libpq's `allow` is
+    /// treated the same, as it only differs in what is tried first.
+    #[default]
+    #[value(alias = "allow")]
+    Prefer,
+    /// Always use TLS, without verifying the certificate of the server
+    Require,
+    /// Always use TLS, and verify that the certificate is signed by a trusted CA and matches the host
+    VerifyFull,
+}
+
+impl FromStr for SslMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "disable" => Ok(SslMode::Disable),
+            "allow" | "prefer" => Ok(SslMode::Prefer),
+            "require" => Ok(SslMode::Require),
+            "verify-full" => Ok(SslMode::VerifyFull),
+            "verify-ca" => Err(anyhow!(
+                "! sslmode 'verify-ca' is not supported, use 'verify-full' to verify the server"
+            )),
+            _ => Err(anyhow!("! unsupported sslmode '{s}'")),
+        }
+    }
+}
+
+impl SslMode {
+    /// The mode to configure tokio-postgres with, which leaves verifying to the TLS connector.
+    pub(crate) fn postgres_mode(self) -> tokio_postgres::config::SslMode {
+        match self {
+            SslMode::Disable => tokio_postgres::config::SslMode::Disable,
+            SslMode::Prefer => tokio_postgres::config::SslMode::Prefer,
+            SslMode::Require | SslMode::VerifyFull => tokio_postgres::config::SslMode::Require,
+        }
+    }
+}
+
+/// Like libpq, `prefer` and `require` only protect against eavesdropping: any certificate is
+/// accepted, as long as the handshake itself is signed correctly.
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Trusts the CAs in the PEM file at `root_cert`, or the Mozilla root certificates if not given.
+fn root_store(root_cert: Option<&Path>) -> Result<RootCertStore> {
+    let Some(root_cert) = root_cert else {
+        return Ok(RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        });
+    };
+
+    let mut store = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(root_cert).context("! failed to read root cert")? {
+        store
+            .add(cert.context("! failed to parse root cert")?)
+            .context("! invalid root cert")?;
+    }
+
+    Ok(store)
+}
+
+pub(crate) fn connector(mode: SslMode, root_cert: Option<&Path>) -> Result<MakeRustlsConnect> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .context("! failed to set up TLS")?;
+
+    let config = match mode {
+        SslMode::VerifyFull => builder
+            .with_root_certificates(root_store(root_cert)?)
+            .with_no_client_auth(),
+        _ => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider)))
+            .with_no_client_auth(),
+    };
+
+    Ok(MakeRustlsConnect::new(config))
+}
@@ -0,0 +1,2 @@
+pub mod gtfs;
+pub mod network;
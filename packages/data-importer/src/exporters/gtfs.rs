@@ -0,0 +1,489 @@
+use crate::db;
+use crate::importers::timetable::{Delivery, load_delivery};
+use crate::logging::logger;
+use anyhow::{Context, Result};
+use deadpool_postgres::Pool;
+use sea_query::{Alias, Expr, PostgresQueryBuilder, Query};
+use sea_query_postgres::PostgresBinder;
+use serde::Serialize;
+use slog::{info, warn};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use zip::ZipWriter;
+use zip::write::SimpleFileOptions;
+
+const TIMEZONE: &str = "Europe/Amsterdam";
+const ROUTE_TYPE_RAIL: u16 = 2;
+
+#[derive(Debug, Serialize)]
+struct AgencyRow {
+    agency_id: String,
+    agency_name: String,
+    agency_url: String,
+    agency_timezone: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct StopRow {
+    stop_id: String,
+    stop_name: String,
+    stop_lat: f64,
+    stop_lon: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct RouteRow {
+    route_id: String,
+    agency_id: String,
+    route_short_name: String,
+    route_type: u16,
+}
+
+#[derive(Debug, Serialize)]
+struct TripRow {
+    route_id: String,
+    service_id: String,
+    trip_id: String,
+    trip_short_name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct StopTimeRow {
+    trip_id: String,
+    arrival_time: String,
+    departure_time: String,
+    stop_id: String,
+    stop_sequence: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct CalendarDateRow {
+    service_id: String,
+    date: String,
+    exception_type: u8,
+}
+
+#[derive(Debug, Default)]
+struct GtfsFeed {
+    agencies: Vec<AgencyRow>,
+    stops: Vec<StopRow>,
+    routes: Vec<RouteRow>,
+    trips: Vec<TripRow>,
+    stop_times: Vec<StopTimeRow>,
+    calendar_dates: Vec<CalendarDateRow>,
+    /// Trips left out because some of their stops have no coordinates
+    skipped_trips: usize,
+}
+
+/// Converts Rijksdriehoek coordinates (in metres) to WGS84 latitude and longitude, using the
+/// approximation by Schreutelkamp and Strang van Hees. Accurate to about a metre.
+fn rd_to_wgs84(x: u32, y: u32) -> (f64, f64) {
+    const LAT_TERMS: [(i32, i32, f64); 11] = [
+        (0, 1, 3235.65389),
+        (2, 0, -32.58297),
+        (0, 2, -0.24750),
+        (2, 1, -0.84978),
+        (0, 3, -0.06550),
+        (2, 2, -0.01709),
+        (1, 0, -0.00738),
+        (4, 0, 0.00530),
+        (2, 3, -0.00039),
+        (4, 1, 0.00033),
+        (1, 1, -0.00012),
+    ];
+    const LON_TERMS: [(i32, i32, f64); 12] = [
+        (1, 0, 5260.52916),
+        (1, 1, 105.94684),
+        (1, 2, 2.45656),
+        (3, 0, -0.81885),
+        (1, 3, 0.05594),
+        (3, 1, -0.05607),
+        (0, 1, 0.01199),
+        (3, 2, -0.00256),
+        (1, 4, 0.00128),
+        (0, 2, 0.00022),
+        (2, 0, -0.00022),
+        (5, 0, 0.00026),
+    ];
+
+    let dx = (x as f64 - 155_000.0) * 1e-5;
+    let dy = (y as f64 - 463_000.0) * 1e-5;
+    let sum = |terms: &[(i32, i32, f64)]| {
+        terms
+            .iter()
+            .map(|(p, q, k)| k * dx.powi(*p) * dy.powi(*q))
+            .sum::<f64>()
+    };
+
+    (
+        52.15517440 + sum(&LAT_TERMS) / 3600.0,
+        5.38720621 + sum(&LON_TERMS) / 3600.0,
+    )
+}
+
+/// GTFS times are relative to noon minus 12 hours, so times past midnight go beyond 24:00:00.
+fn format_gtfs_time(minutes: u32) -> String {
+    format!("{:02}:{:02}:00", minutes / 60, minutes % 60)
+}
+
+fn build_feed(
+    delivery: &Delivery,
+    coordinates: &HashMap<String, (f64, f64)>,
+    agency_url: &str,
+) -> GtfsFeed {
+    let mut feed = GtfsFeed::default();
+
+    let mut used_companies = BTreeSet::new();
+    let mut used_stations = BTreeSet::new();
+    let mut used_routes = BTreeMap::new();
+    let mut used_footnotes = BTreeMap::new();
+
+    for service in &delivery.timetable.data {
+        for leg in service.split_legs().unwrap_or_default() {
+            let Some(train_number) = leg.train_number() else {
+                continue;
+            };
+
+            let Some(company) = delivery
+                .companies
+                .get_by_id(leg.service_number.company_number)
+            else {
+                continue;
+            };
+
+            let footnote = leg.validity.footnote;
+            let running_dates = used_footnotes
+                .entry(footnote)
+                .or_insert_with(|| delivery.running_dates(footnote).unwrap_or_default());
+            if running_dates.is_empty() {
+                continue;
+            }
+
+            let trip_id = format!("{}-{train_number}", leg.service_identification.0);
+
+            let mut stop_times = Vec::new();
+            let mut missing_stations = Vec::new();
+            for ((event, _), (arrival, departure)) in
+                leg.station_events.iter().zip(leg.event_minutes())
+            {
+                let (Some(arrival), Some(departure)) =
+                    (arrival.or(departure), departure.or(arrival))
+                else {
+                    // passages have no times and are no stops in GTFS
+                    continue;
+                };
+
+                if !coordinates.contains_key(&event.station) {
+                    missing_stations.push(event.station.as_str());
+                    continue;
+                }
+
+                stop_times.push(StopTimeRow {
+                    trip_id: trip_id.clone(),
+                    arrival_time: format_gtfs_time(arrival),
+                    departure_time: format_gtfs_time(departure),
+                    stop_id: event.station.clone(),
+                    stop_sequence: stop_times.len() as u32 + 1,
+                });
+            }
+
+            // leaving out the stop would change the trip, so it is left out entirely
+            if !missing_stations.is_empty() {
+                warn!(logger(), "Skipping trip with stops without coordinates";
+                    "trip_id" => trip_id,
+                    "stations" => missing_stations.join(","),
+                );
+                feed.skipped_trips += 1;
+                continue;
+            }
+
+            if stop_times.len() < 2 {
+                continue;
+            }
+
+            let route_id = format!("{}-{}", company.code, leg.transport_mode.code);
+            used_routes
+                .entry(route_id.clone())
+                .or_insert_with(|| RouteRow {
+                    route_id: route_id.clone(),
+                    agency_id: company.code.clone(),
+                    route_short_name: leg.transport_mode.code.clone(),
+                    route_type: ROUTE_TYPE_RAIL,
+                });
+
+            used_companies.insert(company.id);
+            used_stations.extend(stop_times.iter().map(|stop_time| stop_time.stop_id.clone()));
+
+            feed.trips.push(TripRow {
+                route_id,
+                service_id: footnote.to_string(),
+                trip_id,
+                trip_short_name: train_number,
+            });
+            feed.stop_times.extend(stop_times);
+        }
+    }
+
+    feed.agencies = used_companies
+        .into_iter()
+        .filter_map(|id| delivery.companies.get_by_id(id))
+        .map(|company| AgencyRow {
+            agency_id: company.code.clone(),
+            agency_name: company.name.clone(),
+            agency_url: agency_url.to_string(),
+            agency_timezone: TIMEZONE,
+        })
+        .collect();
+
+    // stations from the database may be missing in stations.dat, those get their code as name
+    let names = delivery
+        .stations
+        .data
+        .iter()
+        .map(|station| (station.code.as_str(), station.name.as_str()))
+        .collect::<HashMap<_, _>>();
+
+    feed.stops = used_stations
+        .into_iter()
+        .map(|code| {
+            let (lat, lon) = coordinates[&code];
+            StopRow {
+                stop_name: names
+                    .get(code.as_str())
+                    .unwrap_or(&code.as_str())
+                    .to_string(),
+                stop_id: code,
+                stop_lat: lat,
+                stop_lon: lon,
+            }
+        })
+        .collect();
+
+    feed.routes = used_routes.into_values().collect();
+
+    feed.calendar_dates = used_footnotes
+        .into_iter()
+        .flat_map(|(footnote, dates)| {
+            dates.into_iter().map(move |date| CalendarDateRow {
+                service_id: footnote.to_string(),
+                date: date.format("%Y%m%d").to_string(),
+                exception_type: 1,
+            })
+        })
+        .collect();
+
+    feed
+}
+
+fn write_file<W: Write + std::io::Seek, T: Serialize>(
+    zip: &mut ZipWriter<W>,
+    name: &str,
+    rows: &[T],
+) -> Result<()> {
+    zip.start_file(name, SimpleFileOptions::default())?;
+
+    let mut writer = csv::Writer::from_writer(&mut *zip);
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn write_feed(feed: &GtfsFeed, output_path: &PathBuf) -> Result<()> {
+    let file = File::create(output_path).context("! failed to create output file")?;
+    let mut zip = ZipWriter::new(file);
+
+    write_file(&mut zip, "agency.txt", &feed.agencies)?;
+    write_file(&mut zip, "stops.txt", &feed.stops)?;
+    write_file(&mut zip, "routes.txt", &feed.routes)?;
+    write_file(&mut zip, "trips.txt", &feed.trips)?;
+    write_file(&mut zip, "stop_times.txt", &feed.stop_times)?;
+    write_file(&mut zip, "calendar_dates.txt", &feed.calendar_dates)?;
+
+    zip.finish()?;
+
+    Ok(())
+}
+
+async fn load_database_coordinates(db: &Pool) -> Result<HashMap<String, (f64, f64)>> {
+    let client = db.get().await?;
+    let (sql, params) = Query::select()
+        .column(db::Station::Code)
+        // stored as point(lat, lng)
+        .expr_as(Expr::cust("location[0]"), Alias::new("lat"))
+        .expr_as(Expr::cust("location[1]"), Alias::new("lng"))
+        .from(db::Station::Table)
+        .and_where(Expr::col(db::Station::Location).is_not_null())
+        .build_postgres(PostgresQueryBuilder);
+
+    let rows = client.query(sql.as_str(), &params.as_params()).await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| (row.get("code"), (row.get("lat"), row.get("lng"))))
+        .collect())
+}
+
+/// Converts the delivery at `input_path` into a GTFS zip at `output_path`. Station locations come
+/// from the `station` table if a database is given, and from stations.dat otherwise.
+pub async fn export(
+    input_path: String,
+    output_path: String,
+    agency_url: String,
+    db: Option<Arc<Pool>>,
+) -> Result<()> {
+    let delivery = load_delivery(&PathBuf::from(input_path))?;
+
+    let mut coordinates = delivery
+        .stations
+        .data
+        .iter()
+        .filter_map(|station| {
+            let (x, y) = station.rd_coordinates?;
+            Some((station.code.clone(), rd_to_wgs84(x, y)))
+        })
+        .collect::<HashMap<_, _>>();
+
+    if let Some(db) = db {
+        coordinates.extend(load_database_coordinates(&db).await?);
+    }
+
+    let feed = build_feed(&delivery, &coordinates, &agency_url);
+    info!(logger(), "Exporting GTFS feed";
+        "trips" => feed.trips.len(),
+        "stop_times" => feed.stop_times.len(),
+        "stops" => feed.stops.len(),
+        "skipped_trips" => feed.skipped_trips,
+    );
+
+    write_feed(&feed, &PathBuf::from(&output_path))?;
+    info!(logger(), "Exported GTFS feed"; "path" => output_path);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::importers::timetable::test_support::{IDENTIFICATION, test_delivery};
+
+    #[test]
+    fn it_converts_rd_coordinates() {
+        let (lat, lon) = rd_to_wgs84(155_000, 463_000);
+        assert!((lat - 52.1551744).abs() < 1e-6);
+        assert!((lon - 5.38720621).abs() < 1e-6);
+
+        // Utrecht Dom tower
+        let (lat, lon) = rd_to_wgs84(136_800, 455_900);
+        assert!((lat - 52.0907).abs() < 1e-3);
+        assert!((lon - 5.1214).abs() < 1e-3);
+    }
+
+    #[test]
+    fn it_builds_feed_with_times_past_midnight() {
+        let delivery = test_delivery(
+            IDENTIFICATION,
+            "#00000001\r
+%100,01234,      ,001,003,                              \r
+-00001,000,999\r
+&SPR ,001,003\r
+>gd     ,2348\r
+?1    ,1    ,00001\r
+;gdg\r
++wd     ,2359,2401\r
+?2    ,2    ,00001\r
+<ut     ,2420\r
+?3    ,3    ,00001\r
+",
+            "#00001\r\n10100\r\n",
+            "100,NS        ,Nederlandse Spoorwegen        ,0000\r\n",
+            "1,gd     ,02,02,NL  ,0000,  ,010859,044694,Gouda\r
+0,gdg    ,00,00,NL  ,0000,  ,010940,044674,Gouda Goverwelle\r
+1,wd     ,02,02,NL  ,0000,  ,011734,045604,Woerden\r
+1,ut     ,03,03,NL  ,0000,  ,013605,045571,Utrecht Centraal\r
+",
+            "",
+        );
+
+        let coordinates = delivery
+            .stations
+            .data
+            .iter()
+            .map(|station| {
+                let (x, y) = station.rd_coordinates.unwrap();
+                (station.code.clone(), rd_to_wgs84(x, y))
+            })
+            .collect();
+
+        let feed = build_feed(&delivery, &coordinates, "https://www.ns.nl");
+
+        assert_eq!(feed.agencies.len(), 1);
+        assert_eq!(feed.routes.len(), 1);
+        assert_eq!(feed.trips.len(), 1);
+        assert_eq!(feed.trips[0].trip_id, "1-1234");
+        // the passage at gdg is not a stop
+        assert_eq!(feed.stops.len(), 3);
+
+        let times = feed
+            .stop_times
+            .iter()
+            .map(|st| (st.arrival_time.as_str(), st.departure_time.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            times,
+            vec![
+                ("23:48:00", "23:48:00"),
+                ("23:59:00", "24:01:00"),
+                ("24:20:00", "24:20:00"),
+            ]
+        );
+
+        let dates = feed
+            .calendar_dates
+            .iter()
+            .map(|cd| cd.date.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(dates, vec!["20250601", "20250603"]);
+    }
+
+    #[test]
+    fn it_skips_trips_with_stops_without_coordinates() {
+        let delivery = test_delivery(
+            IDENTIFICATION,
+            "#00000001\r
+%100,01234,      ,001,003,                              \r
+-00000,000,999\r
+&SPR ,001,003\r
+>gd     ,1000\r
+;gdg\r
+.wd     ,1010\r
+<ut     ,1020\r
+",
+            "",
+            "100,NS        ,Nederlandse Spoorwegen        ,0000\r\n",
+            "",
+            "",
+        );
+        let mut coordinates = HashMap::from([
+            ("gd".to_string(), (52.0, 4.7)),
+            ("wd".to_string(), (52.1, 4.9)),
+            ("ut".to_string(), (52.1, 5.1)),
+        ]);
+
+        // the passage at gdg has no coordinates, but isn't a stop either
+        let feed = build_feed(&delivery, &coordinates, "https://www.ns.nl");
+        assert_eq!(feed.trips.len(), 1);
+        assert_eq!(feed.skipped_trips, 0);
+
+        coordinates.remove("wd");
+        let feed = build_feed(&delivery, &coordinates, "https://www.ns.nl");
+        assert!(feed.trips.is_empty());
+        assert!(feed.stop_times.is_empty());
+        assert_eq!(feed.skipped_trips, 1);
+    }
+}
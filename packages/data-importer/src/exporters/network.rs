@@ -0,0 +1,241 @@
+use crate::db;
+use crate::logging::logger;
+use anyhow::{Context, Result};
+use deadpool_postgres::Pool;
+use sea_query::{Alias, Expr, PostgresQueryBuilder, Query};
+use sea_query_postgres::PostgresBinder;
+use serde::{Deserialize, Serialize};
+use slog::{info, warn};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// A GeoJSON geometry, always in longitude/latitude order.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum Geometry {
+    Point { coordinates: (f64, f64) },
+    LineString { coordinates: Vec<(f64, f64)> },
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct StationProperties {
+    kind: &'static str,
+    code: String,
+    uic_code: String,
+    name_long: String,
+    name_medium: Option<String>,
+    name_short: Option<String>,
+    station_type: String,
+    country: String,
+    tracks: Vec<String>,
+    has_travel_assistance: Option<bool>,
+    is_border_stop: Option<bool>,
+    is_available_for_accessible_travel: Option<bool>,
+    has_known_facilities: Option<bool>,
+    are_tracks_independently_accessible: Option<bool>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct TrackProperties {
+    kind: &'static str,
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+enum Properties {
+    Station(StationProperties),
+    Track(TrackProperties),
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Feature {
+    r#type: &'static str,
+    geometry: Geometry,
+    properties: Properties,
+}
+
+impl Feature {
+    fn new(geometry: Geometry, properties: Properties) -> Self {
+        Feature {
+            r#type: "Feature",
+            geometry,
+            properties,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct FeatureCollection {
+    r#type: &'static str,
+    features: Vec<Feature>,
+}
+
+async fn load_stations(db: &Pool) -> Result<Vec<Feature>> {
+    let client = db.get().await?;
+    let (sql, params) = Query::select()
+        .columns([
+            db::Station::Code,
+            db::Station::UicCode,
+            db::Station::NameLong,
+            db::Station::NameMedium,
+            db::Station::NameShort,
+            db::Station::StationType,
+            db::Station::Country,
+            db::Station::Tracks,
+            db::Station::HasTravelAssistance,
+            db::Station::IsBorderStop,
+            db::Station::IsAvailableForAccessibleTravel,
+            db::Station::HasKnownFacilities,
+            db::Station::AreTracksIndependentlyAccessible,
+        ])
+        // stored as point(lat, lng)
+        .expr_as(Expr::cust("location[0]"), Alias::new("lat"))
+        .expr_as(Expr::cust("location[1]"), Alias::new("lng"))
+        .from(db::Station::Table)
+        .and_where(Expr::col(db::Station::DeletedAt).is_null())
+        .build_postgres(PostgresQueryBuilder);
+
+    let rows = client
+        .query(sql.as_str(), &params.as_params())
+        .await
+        .context("! failed to load stations")?;
+
+    let mut features = Vec::with_capacity(rows.len());
+    for row in rows {
+        let code: String = row.get("code");
+        let (Some(lat), Some(lng)) = (
+            row.get::<_, Option<f64>>("lat"),
+            row.get::<_, Option<f64>>("lng"),
+        ) else {
+            warn!(logger(), "Leaving out station without a location"; "code" => code);
+            continue;
+        };
+
+        features.push(Feature::new(
+            Geometry::Point {
+                coordinates: (lng, lat),
+            },
+            Properties::Station(StationProperties {
+                kind: "station",
+                code,
+                uic_code: row.get("uic_code"),
+                name_long: row.get("name_long"),
+                name_medium: row.get("name_medium"),
+                name_short: row.get("name_short"),
+                station_type: row.get("station_type"),
+                country: row.get("country"),
+                tracks: row
+                    .get::<_, Option<Vec<String>>>("tracks")
+                    .unwrap_or_default(),
+                has_travel_assistance: row.get("has_travel_assistance"),
+                is_border_stop: row.get("is_border_stop"),
+                is_available_for_accessible_travel: row.get("is_available_for_accessible_travel"),
+                has_known_facilities: row.get("has_known_facilities"),
+                are_tracks_independently_accessible: row.get("are_tracks_independently_accessible"),
+            }),
+        ));
+    }
+
+    Ok(features)
+}
+
+/// The line strings are stored as GeoJSON geometries already, so they are passed through as is.
+fn track_feature(from: String, to: String, line_string: &str) -> Result<Feature> {
+    let geometry: Geometry = serde_json::from_str(line_string)
+        .with_context(|| format!("! invalid geometry between {from} and {to}"))?;
+
+    Ok(Feature::new(
+        geometry,
+        Properties::Track(TrackProperties {
+            kind: "track",
+            from,
+            to,
+        }),
+    ))
+}
+
+async fn load_tracks(db: &Pool) -> Result<Vec<Feature>> {
+    let client = db.get().await?;
+    let (sql, params) = Query::select()
+        .columns([db::StationGeometry::From, db::StationGeometry::To])
+        .expr(Expr::col(db::StationGeometry::LineString).cast_as(Alias::new("text")))
+        .from(db::StationGeometry::Table)
+        .build_postgres(PostgresQueryBuilder);
+
+    let rows = client
+        .query(sql.as_str(), &params.as_params())
+        .await
+        .context("! failed to load station geometry")?;
+
+    rows.iter()
+        .map(|row| track_feature(row.get(0), row.get(1), row.get(2)))
+        .collect()
+}
+
+/// Writes the stored stations and the track between them as one GeoJSON FeatureCollection at
+/// `output_path`. Features tell stations and tracks apart by their `kind` property.
+pub async fn export(db: Arc<Pool>, output_path: String) -> Result<()> {
+    let stations = load_stations(&db).await?;
+    let tracks = load_tracks(&db).await?;
+    info!(logger(), "Exporting network";
+        "stations" => stations.len(),
+        "tracks" => tracks.len(),
+    );
+
+    let collection = FeatureCollection {
+        r#type: "FeatureCollection",
+        features: stations.into_iter().chain(tracks).collect(),
+    };
+
+    let mut writer = BufWriter::new(
+        File::create(PathBuf::from(&output_path)).context("! failed to create output file")?,
+    );
+    serde_json::to_writer(&mut writer, &collection)?;
+    writer.flush()?;
+    info!(logger(), "Exported network"; "path" => output_path);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_passes_track_geometry_through() {
+        let feature = track_feature(
+            "ut".to_string(),
+            "htn".to_string(),
+            r#"{"type": "LineString", "coordinates": [[5.1101, 52.0894], [5.1695, 52.0305]]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&feature).unwrap(),
+            serde_json::json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "LineString",
+                    "coordinates": [[5.1101, 52.0894], [5.1695, 52.0305]],
+                },
+                "properties": {"kind": "track", "from": "ut", "to": "htn"},
+            })
+        );
+    }
+
+    #[test]
+    fn it_rejects_other_geometries() {
+        assert!(
+            track_feature(
+                "ut".to_string(),
+                "htn".to_string(),
+                r#"{"type": "Polygon"}"#
+            )
+            .is_err()
+        );
+    }
+}
@@ -1,3 +1,6 @@
+pub mod gtfs;
+pub(crate) mod journeys;
+pub mod netex;
 pub mod station_geometry;
 pub mod stations;
 pub mod timetable;
@@ -0,0 +1,597 @@
+use crate::db;
+use crate::importers::journeys::{ImportedJourney, ImportedJourneyEvent, store_journeys};
+use crate::importers::timetable::parsers::company::Company;
+use crate::importers::timetable::parsers::service::station_event::StationEventType;
+use crate::importers::timetable::{WorkerOptions, load_companies};
+use crate::logging::logger;
+use crate::progress::ImportSummary;
+use anyhow::{Context, Result, anyhow};
+use chrono::{Datelike, NaiveDate, NaiveTime, Weekday};
+use deadpool_postgres::Pool;
+use sea_query::{PostgresQueryBuilder, Query};
+use sea_query_postgres::PostgresBinder;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use slog::{info, warn};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::PathBuf;
+use std::sync::Arc;
+use zip::ZipArchive;
+
+#[derive(Debug, Deserialize)]
+struct AgencyRecord {
+    agency_id: Option<String>,
+    agency_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct StopRecord {
+    stop_id: String,
+    stop_code: Option<String>,
+    parent_station: Option<String>,
+    platform_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RouteRecord {
+    route_id: String,
+    agency_id: Option<String>,
+    route_short_name: Option<String>,
+    route_type: u16,
+}
+
+#[derive(Debug, Deserialize)]
+struct TripRecord {
+    route_id: String,
+    service_id: String,
+    trip_id: String,
+    trip_short_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StopTimeRecord {
+    trip_id: String,
+    arrival_time: Option<String>,
+    departure_time: Option<String>,
+    stop_id: String,
+    stop_sequence: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct CalendarRecord {
+    service_id: String,
+    monday: u8,
+    tuesday: u8,
+    wednesday: u8,
+    thursday: u8,
+    friday: u8,
+    saturday: u8,
+    sunday: u8,
+    start_date: String,
+    end_date: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CalendarDateRecord {
+    service_id: String,
+    date: String,
+    exception_type: u8,
+}
+
+/// The files of a GTFS feed that are needed to create services, journeys and journey events.
+#[derive(Debug, Default)]
+struct GtfsFeed {
+    agencies: Vec<AgencyRecord>,
+    stops: HashMap<String, StopRecord>,
+    routes: HashMap<String, RouteRecord>,
+    trips: Vec<TripRecord>,
+    stop_times: HashMap<String, Vec<StopTimeRecord>>,
+    service_dates: HashMap<String, BTreeSet<NaiveDate>>,
+}
+
+fn read_records<T: DeserializeOwned, R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+) -> Result<Option<Vec<T>>> {
+    let file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let records = csv::Reader::from_reader(file)
+        .deserialize()
+        .collect::<Result<Vec<T>, _>>()
+        .context(format!("! failed to parse {name}"))?;
+
+    Ok(Some(records))
+}
+
+fn parse_date(date: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y%m%d").context(format!("! invalid date {date}"))
+}
+
+/// GTFS times can go beyond 24:00:00 for trips running past midnight, while the database stores
+/// them modulo 24 hours just like the IFF timetable does.
+fn parse_time(time: &str) -> Result<NaiveTime> {
+    let parts = time
+        .trim()
+        .split(':')
+        .map(str::parse::<u32>)
+        .collect::<Result<Vec<_>, _>>()
+        .context(format!("! invalid time {time}"))?;
+
+    let [hours, minutes, seconds] = parts[..] else {
+        return Err(anyhow!("! invalid time {time}"));
+    };
+
+    NaiveTime::from_hms_opt(hours % 24, minutes, seconds).context(format!("! invalid time {time}"))
+}
+
+fn service_dates(
+    calendars: Vec<CalendarRecord>,
+    calendar_dates: Vec<CalendarDateRecord>,
+) -> Result<HashMap<String, BTreeSet<NaiveDate>>> {
+    let mut service_dates: HashMap<String, BTreeSet<NaiveDate>> = HashMap::new();
+
+    for calendar in calendars {
+        let weekdays = [
+            (Weekday::Mon, calendar.monday),
+            (Weekday::Tue, calendar.tuesday),
+            (Weekday::Wed, calendar.wednesday),
+            (Weekday::Thu, calendar.thursday),
+            (Weekday::Fri, calendar.friday),
+            (Weekday::Sat, calendar.saturday),
+            (Weekday::Sun, calendar.sunday),
+        ]
+        .into_iter()
+        .filter(|(_, runs)| *runs == 1)
+        .map(|(weekday, _)| weekday)
+        .collect::<Vec<_>>();
+
+        let end_date = parse_date(&calendar.end_date)?;
+        let dates = parse_date(&calendar.start_date)?
+            .iter_days()
+            .take_while(|date| *date <= end_date)
+            .filter(|date| weekdays.contains(&date.weekday()));
+
+        service_dates
+            .entry(calendar.service_id)
+            .or_default()
+            .extend(dates);
+    }
+
+    for calendar_date in calendar_dates {
+        let date = parse_date(&calendar_date.date)?;
+        let dates = service_dates.entry(calendar_date.service_id).or_default();
+        match calendar_date.exception_type {
+            1 => dates.insert(date),
+            2 => dates.remove(&date),
+            _ => continue,
+        };
+    }
+
+    Ok(service_dates)
+}
+
+fn read_feed<R: Read + Seek>(reader: R) -> Result<GtfsFeed> {
+    let mut archive = ZipArchive::new(reader).context("! failed to open GTFS zip")?;
+
+    let agencies = read_records::<AgencyRecord, _>(&mut archive, "agency.txt")?
+        .context("! agency.txt missing from GTFS zip")?;
+    let stops = read_records::<StopRecord, _>(&mut archive, "stops.txt")?
+        .context("! stops.txt missing from GTFS zip")?;
+    let routes = read_records::<RouteRecord, _>(&mut archive, "routes.txt")?
+        .context("! routes.txt missing from GTFS zip")?;
+    let trips = read_records::<TripRecord, _>(&mut archive, "trips.txt")?
+        .context("! trips.txt missing from GTFS zip")?;
+    let stop_times = read_records::<StopTimeRecord, _>(&mut archive, "stop_times.txt")?
+        .context("! stop_times.txt missing from GTFS zip")?;
+
+    // a feed needs at least one of both calendar files
+    let calendars =
+        read_records::<CalendarRecord, _>(&mut archive, "calendar.txt")?.unwrap_or_default();
+    let calendar_dates = read_records::<CalendarDateRecord, _>(&mut archive, "calendar_dates.txt")?
+        .unwrap_or_default();
+
+    let mut stop_times_per_trip: HashMap<String, Vec<StopTimeRecord>> = HashMap::new();
+    for stop_time in stop_times {
+        stop_times_per_trip
+            .entry(stop_time.trip_id.clone())
+            .or_default()
+            .push(stop_time);
+    }
+    for stop_times in stop_times_per_trip.values_mut() {
+        stop_times.sort_by_key(|stop_time| stop_time.stop_sequence);
+    }
+
+    Ok(GtfsFeed {
+        agencies,
+        stops: stops
+            .into_iter()
+            .map(|stop| (stop.stop_id.clone(), stop))
+            .collect(),
+        routes: routes
+            .into_iter()
+            .map(|route| (route.route_id.clone(), route))
+            .collect(),
+        trips,
+        stop_times: stop_times_per_trip,
+        service_dates: service_dates(calendars, calendar_dates)?,
+    })
+}
+
+/// Finds the station code for a GTFS stop by looking up its stop code or id, and those of its
+/// parent station, in the UIC codes of the `station` table.
+fn station_code<'a>(
+    feed: &GtfsFeed,
+    stop: &StopRecord,
+    stations_by_uic: &'a HashMap<String, String>,
+) -> Option<&'a String> {
+    let parent = stop
+        .parent_station
+        .as_ref()
+        .and_then(|parent| feed.stops.get(parent));
+
+    [Some(stop), parent]
+        .into_iter()
+        .flatten()
+        .flat_map(|stop| [stop.stop_code.as_ref(), Some(&stop.stop_id)])
+        .flatten()
+        .find_map(|candidate| stations_by_uic.get(candidate))
+}
+
+/// Railway routes, using either the basic route type or one of the extended railway types.
+fn is_rail(route: &RouteRecord) -> bool {
+    route.route_type == 2 || (100..=117).contains(&route.route_type)
+}
+
+/// The company.dat code of an agency, which is what the IFF import stores as the provider of a
+/// service. Agencies are matched by name first, as feeds tend to use their own agency ids.
+fn company_code<'a>(agency: &AgencyRecord, companies: &'a [Company]) -> Option<&'a str> {
+    let names = [Some(&agency.agency_name), agency.agency_id.as_ref()];
+    let matches = |value: &str| {
+        names
+            .iter()
+            .flatten()
+            .any(|name| name.trim().eq_ignore_ascii_case(value))
+    };
+
+    companies
+        .iter()
+        .find(|company| matches(&company.name))
+        .or_else(|| companies.iter().find(|company| matches(&company.code)))
+        .map(|company| company.code.as_str())
+}
+
+/// The provider to store the trips of every agency under: the matching company.dat code, so a
+/// train number the IFF import already stored isn't taken for one of another provider, or the
+/// agency's own id otherwise.
+fn providers(agencies: &[AgencyRecord], companies: &[Company]) -> Vec<String> {
+    agencies
+        .iter()
+        .map(|agency| {
+            company_code(agency, companies)
+                .map(str::to_string)
+                .or_else(|| agency.agency_id.clone())
+                .unwrap_or_else(|| agency.agency_name.clone())
+        })
+        .collect()
+}
+
+fn build_journeys(
+    feed: &GtfsFeed,
+    stations_by_uic: &HashMap<String, String>,
+    companies: &[Company],
+) -> Result<Vec<ImportedJourney>> {
+    let mut journeys = Vec::new();
+    let providers = providers(&feed.agencies, companies);
+
+    for trip in &feed.trips {
+        let Some(train_number) = trip.trip_short_name.clone() else {
+            continue;
+        };
+
+        let route = feed
+            .routes
+            .get(&trip.route_id)
+            .context(format!("! route {} not found", trip.route_id))?;
+        if !is_rail(route) {
+            continue;
+        }
+
+        // agency_id may be left out if the feed only has a single agency
+        let agency = feed
+            .agencies
+            .iter()
+            .position(|agency| agency.agency_id == route.agency_id || feed.agencies.len() == 1)
+            .context(format!("! agency of route {} not found", route.route_id))?;
+
+        let running_on = feed
+            .service_dates
+            .get(&trip.service_id)
+            .map(|dates| dates.iter().copied().collect::<Vec<_>>())
+            .unwrap_or_default();
+        if running_on.is_empty() {
+            continue;
+        }
+
+        let mut events = Vec::new();
+        for stop_time in feed.stop_times.get(&trip.trip_id).into_iter().flatten() {
+            let Some(stop) = feed.stops.get(&stop_time.stop_id) else {
+                continue;
+            };
+            let Some(station) = station_code(feed, stop, stations_by_uic) else {
+                continue;
+            };
+
+            events.push(ImportedJourneyEvent {
+                station: station.clone(),
+                event_type: StationEventType::ShortStop,
+                arrival_time: stop_time
+                    .arrival_time
+                    .as_deref()
+                    .map(parse_time)
+                    .transpose()?,
+                departure_time: stop_time
+                    .departure_time
+                    .as_deref()
+                    .map(parse_time)
+                    .transpose()?,
+                platform: stop.platform_code.clone(),
+            });
+        }
+
+        if events.len() < 2 {
+            continue;
+        }
+
+        if let Some(first) = events.first_mut() {
+            first.event_type = StationEventType::Departure;
+            first.arrival_time = None;
+        }
+        if let Some(last) = events.last_mut() {
+            last.event_type = StationEventType::Arrival;
+            last.departure_time = None;
+        }
+
+        journeys.push(ImportedJourney {
+            source_id: format!("gtfs:{}", trip.trip_id),
+            train_number,
+            service_type: route
+                .route_short_name
+                .clone()
+                .unwrap_or_else(|| route.route_type.to_string()),
+            provider: providers[agency].clone(),
+            running_on,
+            events,
+        });
+    }
+
+    Ok(journeys)
+}
+
+async fn load_stations_by_uic(db: &Pool) -> Result<HashMap<String, String>> {
+    let client = db.get().await?;
+    let (sql, params) = Query::select()
+        .columns([db::Station::UicCode, db::Station::Code])
+        .from(db::Station::Table)
+        .build_postgres(PostgresQueryBuilder);
+
+    let rows = client.query(sql.as_str(), &params.as_params()).await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| (row.get("uic_code"), row.get("code")))
+        .collect())
+}
+
+/// Imports the rail trips of the GTFS zip at `input_path` as services, journeys and journey
+/// events. Stops are matched to stations by UIC code, stops that can't be matched are left out and
+/// trips with less than two matched stops are skipped entirely. Agencies are matched to the
+/// companies of the IFF delivery at `delivery_path`, if given, to share their providers.
+pub async fn import(
+    db: Arc<Pool>,
+    input_path: String,
+    delivery_path: Option<String>,
+    options: WorkerOptions,
+) -> Result<ImportSummary> {
+    info!(logger(), "Using input path"; "path" => &input_path);
+    let file = File::open(&input_path).context("! failed to open GTFS zip")?;
+    let feed = read_feed(file)?;
+
+    let companies = match delivery_path {
+        Some(delivery_path) => load_companies(&PathBuf::from(delivery_path))?.data,
+        None => Vec::new(),
+    };
+    for agency in &feed.agencies {
+        if company_code(agency, &companies).is_none() {
+            warn!(logger(), "Agency does not match a company of the delivery";
+                "agency" => &agency.agency_name,
+            );
+        }
+    }
+
+    info!(logger(), "Loaded GTFS feed";
+        "agencies" => feed.agencies.len(),
+        "stops" => feed.stops.len(),
+        "trips" => feed.trips.len(),
+    );
+
+    let stations_by_uic = load_stations_by_uic(&db).await?;
+    let journeys = build_journeys(&feed, &stations_by_uic, &companies)?;
+    info!(logger(), "Matched trips to stations";
+        "matched" => journeys.len(),
+        "trips" => feed.trips.len(),
+    );
+
+    store_journeys(db, journeys, options).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::importers::timetable::test_support::{IDENTIFICATION, test_delivery};
+    use std::io::{Cursor, Write};
+    use zip::ZipWriter;
+    use zip::write::SimpleFileOptions;
+
+    fn feed_zip(files: &[(&str, &str)]) -> Cursor<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+
+        let mut cursor = zip.finish().unwrap();
+        cursor.set_position(0);
+        cursor
+    }
+
+    #[test]
+    fn it_expands_calendars() {
+        let calendars = vec![CalendarRecord {
+            service_id: "1".to_string(),
+            monday: 1,
+            tuesday: 0,
+            wednesday: 1,
+            thursday: 0,
+            friday: 0,
+            saturday: 0,
+            sunday: 0,
+            start_date: "20250602".to_string(),
+            end_date: "20250615".to_string(),
+        }];
+        let calendar_dates = vec![
+            CalendarDateRecord {
+                service_id: "1".to_string(),
+                date: "20250604".to_string(),
+                exception_type: 2,
+            },
+            CalendarDateRecord {
+                service_id: "2".to_string(),
+                date: "20250607".to_string(),
+                exception_type: 1,
+            },
+        ];
+
+        let dates = service_dates(calendars, calendar_dates).unwrap();
+        let date = |day| NaiveDate::from_ymd_opt(2025, 6, day).unwrap();
+
+        assert_eq!(dates["1"], BTreeSet::from([date(2), date(9), date(11)]));
+        assert_eq!(dates["2"], BTreeSet::from([date(7)]));
+    }
+
+    fn arriva_feed() -> GtfsFeed {
+        read_feed(feed_zip(&[
+            (
+                "agency.txt",
+                "agency_id,agency_name,agency_url,agency_timezone\nARR,Arriva,https://arriva.nl,Europe/Amsterdam\n",
+            ),
+            (
+                "stops.txt",
+                "\u{feff}stop_id,stop_code,stop_name,location_type,parent_station,platform_code\n\
+                 stoparea:1,8400219,Groningen,1,,\n\
+                 1001,,Groningen,0,stoparea:1,3b\n\
+                 1002,8400388,Leeuwarden,0,,8\n\
+                 2001,,Bus stop,0,,\n",
+            ),
+            (
+                "routes.txt",
+                "route_id,agency_id,route_short_name,route_type\n10,ARR,Stoptrein,2\n20,ARR,Q1,3\n",
+            ),
+            (
+                "trips.txt",
+                "route_id,service_id,trip_id,trip_short_name\n10,1,t1,37099\n10,1,t2,\n20,1,t3,37100\n",
+            ),
+            (
+                "stop_times.txt",
+                "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+                 t1,25:05:00,25:05:00,1002,2\n\
+                 t1,24:10:00,24:10:00,1001,1\n\
+                 t1,25:10:00,25:10:00,2001,3\n\
+                 t3,10:00:00,10:00:00,1001,1\n\
+                 t3,11:00:00,11:00:00,1002,2\n",
+            ),
+            ("calendar_dates.txt", "service_id,date,exception_type\n1,20250602,1\n"),
+        ]))
+        .unwrap()
+    }
+
+    fn stations_by_uic() -> HashMap<String, String> {
+        HashMap::from([
+            ("8400219".to_string(), "gn".to_string()),
+            ("8400388".to_string(), "lw".to_string()),
+        ])
+    }
+
+    #[test]
+    fn it_builds_journeys_for_matched_stations() {
+        let feed = arriva_feed();
+
+        let journeys = build_journeys(&feed, &stations_by_uic(), &[]).unwrap();
+        let time = |h, m| NaiveTime::from_hms_opt(h, m, 0);
+
+        assert_eq!(
+            journeys,
+            vec![ImportedJourney {
+                source_id: "gtfs:t1".to_string(),
+                train_number: "37099".to_string(),
+                service_type: "Stoptrein".to_string(),
+                provider: "ARR".to_string(),
+                running_on: vec![NaiveDate::from_ymd_opt(2025, 6, 2).unwrap()],
+                events: vec![
+                    ImportedJourneyEvent {
+                        station: "gn".to_string(),
+                        event_type: StationEventType::Departure,
+                        arrival_time: None,
+                        departure_time: time(0, 10),
+                        platform: Some("3b".to_string()),
+                    },
+                    ImportedJourneyEvent {
+                        station: "lw".to_string(),
+                        event_type: StationEventType::Arrival,
+                        arrival_time: time(1, 5),
+                        departure_time: None,
+                        platform: Some("8".to_string()),
+                    },
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn it_stores_trips_under_the_providers_of_the_iff_import() {
+        let delivery = test_delivery(
+            IDENTIFICATION,
+            "#00000001\r
+%500,37099,      ,001,002,                              \r
+-00000,000,999\r
+&ST  ,001,002\r
+>gn     ,0010\r
+<lw     ,0105\r
+",
+            "",
+            "500,Arriva    ,Arriva                        ,0000\r
+550,Noord     ,Arriva Noord                  ,0000\r
+",
+            "",
+            "",
+        );
+        // the provider the IFF import stores for train number 37099
+        let leg = &delivery.timetable.data[0].split_legs().unwrap()[0];
+        let iff_provider = &delivery
+            .companies
+            .get_by_id(leg.service_number.company_number)
+            .unwrap()
+            .code;
+
+        let journeys =
+            build_journeys(&arriva_feed(), &stations_by_uic(), &delivery.companies.data).unwrap();
+
+        assert_eq!(journeys[0].train_number, "37099");
+        assert_eq!(&journeys[0].provider, iff_provider);
+    }
+}
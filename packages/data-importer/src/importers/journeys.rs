@@ -0,0 +1,358 @@
+use crate::db::{self, retry_transient};
+use crate::importers::timetable::WorkerOptions;
+use crate::importers::timetable::parsers::service::station_event::StationEventType;
+use crate::instrumentation::{in_span, metrics, timed};
+use crate::logging::logger;
+use crate::progress::{ImportSummary, PROGRESS_INTERVAL, Progress};
+use anyhow::{Context, Result, anyhow};
+use chrono::{NaiveDate, NaiveTime};
+use deadpool_postgres::Pool;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::{Context as TraceContext, KeyValue};
+use sea_query::{Alias, Expr, Func, IntoIden, OnConflict, PostgresQueryBuilder, Query, SimpleExpr};
+use sea_query_postgres::PostgresBinder;
+use slog::{debug, error};
+use std::sync::Arc;
+use uuid::Uuid;
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct ImportedJourneyEvent {
+    pub station: String,
+    pub event_type: StationEventType,
+    pub arrival_time: Option<NaiveTime>,
+    pub departure_time: Option<NaiveTime>,
+    pub platform: Option<String>,
+}
+
+/// A trip from a source other than IFF mapped onto a service, with a journey for every date it
+/// runs on. `source_id` ends up in the `source_ids` of each journey.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ImportedJourney {
+    pub source_id: String,
+    pub train_number: String,
+    pub service_type: String,
+    pub provider: String,
+    pub running_on: Vec<NaiveDate>,
+    pub events: Vec<ImportedJourneyEvent>,
+}
+
+/// The new value of a journey event column, or the stored one if the new value is missing.
+fn keep_when_missing(column: db::JourneyEvent) -> SimpleExpr {
+    let column = column.into_iden();
+    Func::coalesce([
+        Expr::col((Alias::new("excluded"), column.clone())).into(),
+        Expr::col((db::JourneyEvent::Table, column)).into(),
+    ])
+    .into()
+}
+
+struct ImportJob {
+    db: Arc<Pool>,
+    journey: ImportedJourney,
+    trace_context: TraceContext,
+}
+
+impl ImportJob {
+    async fn process(&self, worker_id: usize) -> Result<()> {
+        debug!(logger(), "Processing journey";
+            "journey" => &self.journey.source_id,
+            "worker" => worker_id,
+        );
+
+        let mut db = timed("get_client", self.db.get())
+            .await
+            .context("worker failed to get client from pool")?;
+
+        let transaction = db
+            .transaction()
+            .await
+            .context("failed to start transaction")?;
+
+        let (service_sql, service_params) = Query::insert()
+            .into_table(db::Service::Table)
+            .columns([
+                db::Service::TrainNumber,
+                db::Service::Type,
+                db::Service::Provider,
+            ])
+            .values_panic([
+                self.journey.train_number.clone().into(),
+                self.journey.service_type.clone().into(),
+                self.journey.provider.clone().into(),
+            ])
+            .on_conflict(
+                OnConflict::columns([db::Service::TrainNumber, db::Service::TimetableYear])
+                    .update_column(db::Service::TrainNumber)
+                    // train numbers of other providers are not merged into this one
+                    .action_and_where(
+                        Expr::col((db::Service::Table, db::Service::Provider))
+                            .equals((Alias::new("excluded"), db::Service::Provider)),
+                    )
+                    .to_owned(),
+            )
+            .returning(Query::returning().column(db::Service::Id))
+            .build_postgres(PostgresQueryBuilder);
+
+        let inserted_service = timed(
+            "insert_service",
+            transaction.query_opt(service_sql.as_str(), &service_params.as_params()),
+        )
+        .await
+        .context("! failed to insert service")?
+        .ok_or_else(|| {
+            anyhow!(
+                "! train number {} is already used by a provider other than {}",
+                self.journey.train_number,
+                self.journey.provider
+            )
+        })?;
+        let service_id: Uuid = inserted_service.get("id");
+
+        let mut journey_event_insert = Query::insert();
+        journey_event_insert
+            .into_table(db::JourneyEvent::Table)
+            .columns([
+                db::JourneyEvent::JourneyId,
+                db::JourneyEvent::Station,
+                db::JourneyEvent::EventTypePlanned,
+                db::JourneyEvent::StopOrder,
+                db::JourneyEvent::ArrivalTimePlanned,
+                db::JourneyEvent::ArrivalPlatformPlanned,
+                db::JourneyEvent::DepartureTimePlanned,
+                db::JourneyEvent::DeparturePlatformPlanned,
+            ])
+            .on_conflict(
+                OnConflict::columns([db::JourneyEvent::JourneyId, db::JourneyEvent::StopOrder])
+                    .update_columns([
+                        db::JourneyEvent::Station,
+                        db::JourneyEvent::EventTypePlanned,
+                        db::JourneyEvent::ArrivalTimePlanned,
+                        db::JourneyEvent::DepartureTimePlanned,
+                    ])
+                    // sources without platforms, like NeTEx, keep the ones from IFF
+                    .values([
+                        (
+                            db::JourneyEvent::ArrivalPlatformPlanned,
+                            keep_when_missing(db::JourneyEvent::ArrivalPlatformPlanned),
+                        ),
+                        (
+                            db::JourneyEvent::DeparturePlatformPlanned,
+                            keep_when_missing(db::JourneyEvent::DeparturePlatformPlanned),
+                        ),
+                    ])
+                    .to_owned(),
+            );
+
+        for running_on in &self.journey.running_on {
+            let (journey_sql, journey_params) = Query::insert()
+                .into_table(db::Journey::Table)
+                .columns([
+                    db::Journey::ServiceId,
+                    db::Journey::RunningOn,
+                    db::Journey::SourceIds,
+                ])
+                .values_panic([
+                    service_id.into(),
+                    (*running_on).into(),
+                    vec![self.journey.source_id.clone()].into(),
+                ])
+                .on_conflict(
+                    OnConflict::columns([db::Journey::ServiceId, db::Journey::RunningOn])
+                        .value(
+                            db::Journey::SourceIds,
+                            Expr::cust("ARRAY(SELECT DISTINCT unnest(array_cat(\"journey\".\"source_ids\", \"excluded\".\"source_ids\")))"),
+                        )
+                        .to_owned(),
+                )
+                .returning(Query::returning().column(db::Journey::Id))
+                .build_postgres(PostgresQueryBuilder);
+
+            let inserted_journey = timed(
+                "insert_journey",
+                transaction.query_one(journey_sql.as_str(), &journey_params.as_params()),
+            )
+            .await
+            .context("! failed to insert journey")?;
+            let journey_id: Uuid = inserted_journey.get("id");
+
+            // a shorter stop pattern than the stored one would leave its last stops behind
+            let (delete_sql, delete_params) = Query::delete()
+                .from_table(db::JourneyEvent::Table)
+                .and_where(Expr::col(db::JourneyEvent::JourneyId).eq(journey_id))
+                .and_where(
+                    Expr::col(db::JourneyEvent::StopOrder).gte(self.journey.events.len() as u64),
+                )
+                .build_postgres(PostgresQueryBuilder);
+
+            timed(
+                "delete_journey_events",
+                transaction.execute(delete_sql.as_str(), &delete_params.as_params()),
+            )
+            .await
+            .context("! failed to delete trailing journey events")?;
+
+            for (idx, event) in self.journey.events.iter().enumerate() {
+                journey_event_insert.values_panic([
+                    journey_id.into(),
+                    event.station.clone().into(),
+                    event.event_type.to_string().into(),
+                    (idx as u64).into(),
+                    event.arrival_time.into(),
+                    event.platform.clone().into(),
+                    event.departure_time.into(),
+                    event.platform.clone().into(),
+                ]);
+            }
+        }
+
+        let journey_event_insert_query = journey_event_insert.to_string(PostgresQueryBuilder);
+        timed(
+            "insert_journey_events",
+            transaction.batch_execute(journey_event_insert_query.as_str()),
+        )
+        .await
+        .context("! could not insert journey events")?;
+
+        timed("commit", transaction.commit())
+            .await
+            .context("! could not commit transaction")?;
+
+        let journeys_written = self.journey.running_on.len() as u64;
+        metrics().record_rows("service", 1);
+        metrics().record_rows("journey", journeys_written);
+        metrics().record_rows(
+            "journey_event",
+            journeys_written * self.journey.events.len() as u64,
+        );
+
+        Ok(())
+    }
+}
+
+async fn worker(
+    id: usize,
+    job_rx: async_channel::Receiver<ImportJob>,
+    result_tx: async_channel::Sender<(String, Result<()>)>,
+    options: WorkerOptions,
+) {
+    debug!(logger(), "Worker started"; "worker" => id);
+    while let Ok(job) = job_rx.recv().await {
+        let source_id = job.journey.source_id.clone();
+        let trace_context = job.trace_context.clone();
+        let attributes = vec![
+            KeyValue::new("journey", source_id.clone()),
+            KeyValue::new("worker", id as i64),
+        ];
+        let process = retry_transient(format!("journey {source_id}"), options.max_retries, || {
+            let process = tokio::time::timeout(options.service_timeout, job.process(id));
+            async move {
+                process.await.unwrap_or_else(|_| {
+                    Err(anyhow!(
+                        "! timed out after {}s",
+                        options.service_timeout.as_secs()
+                    ))
+                })
+            }
+        });
+        let output = in_span("process_journey", attributes, process)
+            .with_context(trace_context)
+            .await;
+        let _ = result_tx.send((source_id, output)).await;
+    }
+    debug!(logger(), "Worker exiting"; "worker" => id);
+}
+
+async fn collect_results(
+    rx: async_channel::Receiver<(String, Result<()>)>,
+    total: usize,
+) -> ImportSummary {
+    let mut progress = Progress::new("journeys", total);
+    let mut report = tokio::time::interval_at(
+        tokio::time::Instant::now() + PROGRESS_INTERVAL,
+        PROGRESS_INTERVAL,
+    );
+
+    loop {
+        let (source_id, result) = tokio::select! {
+            received = rx.recv() => match received {
+                Ok(received) => received,
+                Err(_) => break,
+            },
+            _ = report.tick() => {
+                progress.log();
+                continue;
+            }
+        };
+
+        match result {
+            Ok(()) => {
+                metrics().record_service("processed");
+                progress.processed();
+                debug!(logger(), "Journey processed"; "journey" => source_id);
+            }
+            Err(e) => {
+                metrics().record_service("failed");
+                error!(logger(), "Failed to process journey";
+                    "journey" => &source_id,
+                    "error" => format!("{e:#}"),
+                );
+                progress.failed(source_id, &e);
+            }
+        }
+    }
+
+    progress.finish()
+}
+
+/// Creates services, journeys and journey events for journeys that were mapped from another
+/// timetable format, using the same worker setup as the IFF import.
+pub(crate) async fn store_journeys(
+    db: Arc<Pool>,
+    journeys: Vec<ImportedJourney>,
+    options: WorkerOptions,
+) -> Result<ImportSummary> {
+    let attributes = vec![KeyValue::new("journeys", journeys.len() as i64)];
+    in_span(
+        "store_journeys",
+        attributes,
+        process_journeys(db, journeys, options),
+    )
+    .await
+}
+
+async fn process_journeys(
+    db: Arc<Pool>,
+    journeys: Vec<ImportedJourney>,
+    options: WorkerOptions,
+) -> Result<ImportSummary> {
+    let (job_tx, job_rx) = async_channel::bounded::<ImportJob>(options.queue_capacity);
+    let (result_tx, result_rx) = async_channel::unbounded::<(String, Result<()>)>();
+
+    let worker_handles = (0..options.workers)
+        .map(|id| tokio::spawn(worker(id, job_rx.clone(), result_tx.clone(), options)))
+        .collect::<Vec<_>>();
+    drop(result_tx);
+
+    let collector_handle = tokio::spawn(collect_results(result_rx, journeys.len()));
+
+    for journey in journeys {
+        let job = ImportJob {
+            db: Arc::clone(&db),
+            journey,
+            trace_context: TraceContext::current(),
+        };
+
+        if job_tx.send(job).await.is_err() {
+            error!(logger(), "Job receiver has been dropped, aborting");
+            break;
+        }
+    }
+
+    drop(job_tx);
+
+    for handle in worker_handles {
+        handle.await?;
+    }
+
+    Ok(collector_handle.await?)
+}
@@ -0,0 +1,631 @@
+use crate::importers::journeys::{ImportedJourney, ImportedJourneyEvent, store_journeys};
+use crate::importers::timetable::WorkerOptions;
+use crate::importers::timetable::parsers::service::station_event::StationEventType;
+use crate::logging::logger;
+use crate::progress::ImportSummary;
+use anyhow::{Context, Result, anyhow};
+use chrono::{NaiveDate, NaiveTime};
+use deadpool_postgres::Pool;
+use quick_xml::Reader;
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::{BytesStart, Event};
+use slog::info;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug, Default)]
+struct StopPointInJourneyPattern {
+    id: String,
+    order: u32,
+    scheduled_stop_point: String,
+    for_alighting: Option<bool>,
+    for_boarding: Option<bool>,
+}
+
+/// Day offsets are left out, the database only stores times modulo 24 hours just like for IFF.
+#[derive(Debug, Default)]
+struct TimetabledPassingTime {
+    stop_point_in_journey_pattern: String,
+    arrival_time: Option<NaiveTime>,
+    departure_time: Option<NaiveTime>,
+}
+
+#[derive(Debug, Default)]
+struct ServiceJourney {
+    id: String,
+    private_code: Option<String>,
+    transport_mode: Option<String>,
+    product_category: Option<String>,
+    operator: Option<String>,
+    journey_pattern: Option<String>,
+    day_types: Vec<String>,
+    passing_times: Vec<TimetabledPassingTime>,
+}
+
+#[derive(Debug, Default)]
+struct OperatingPeriod {
+    id: String,
+    from_date: Option<NaiveDate>,
+    valid_day_bits: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct DayTypeAssignment {
+    day_type: String,
+    date: Option<NaiveDate>,
+    operating_period: Option<String>,
+    is_available: Option<bool>,
+}
+
+/// The parts of a NeTEx publication that are needed to create journeys. Everything is referenced
+/// by id, as NeTEx files are free to define elements after the ones that use them.
+#[derive(Debug, Default)]
+struct NetexData {
+    /// station code per ScheduledStopPoint id
+    scheduled_stop_points: HashMap<String, String>,
+    /// short name per Operator id
+    operators: HashMap<String, String>,
+    journey_patterns: HashMap<String, Vec<StopPointInJourneyPattern>>,
+    service_journeys: Vec<ServiceJourney>,
+    operating_periods: HashMap<String, OperatingPeriod>,
+    day_type_assignments: Vec<DayTypeAssignment>,
+}
+
+/// The NeTEx element currently being read. Only one of each can be open at a time, nested
+/// elements (like passing times in a journey) are tracked separately.
+#[derive(Debug, Default)]
+struct ParserState {
+    path: Vec<String>,
+    text: String,
+
+    scheduled_stop_point: Option<(String, Option<String>)>,
+    operator: Option<(String, Option<String>)>,
+    journey_pattern: Option<(String, Vec<StopPointInJourneyPattern>)>,
+    stop_point: Option<StopPointInJourneyPattern>,
+    service_journey: Option<ServiceJourney>,
+    passing_time: Option<TimetabledPassingTime>,
+    operating_period: Option<OperatingPeriod>,
+    day_type_assignment: Option<DayTypeAssignment>,
+}
+
+fn attribute(element: &BytesStart, name: &str) -> Result<Option<String>> {
+    Ok(element
+        .try_get_attribute(name)?
+        .map(|attribute| attribute.unescape_value())
+        .transpose()?
+        .map(|value| value.into_owned()))
+}
+
+/// Station codes are the last part of the id in the Dutch profile, e.g. `NS:ScheduledStopPoint:ut`,
+/// unless a private code is given.
+fn id_suffix(id: &str) -> &str {
+    id.rsplit(':').next().unwrap_or(id)
+}
+
+fn parse_date(date: &str) -> Result<NaiveDate> {
+    // both plain dates and date times at midnight are used
+    let date = date.get(..10).unwrap_or(date);
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").context(format!("! invalid date {date}"))
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M:%S").context(format!("! invalid time {time}"))
+}
+
+fn parse_bool(value: &str) -> bool {
+    value == "true" || value == "1"
+}
+
+impl ParserState {
+    fn parent(&self) -> Option<&str> {
+        self.path.iter().rev().nth(1).map(String::as_str)
+    }
+
+    fn start(&mut self, element: &BytesStart) -> Result<()> {
+        let id = || attribute(element, "id").map(Option::unwrap_or_default);
+        let reference = || attribute(element, "ref").map(Option::unwrap_or_default);
+
+        match element.local_name().as_ref() {
+            b"ScheduledStopPoint" => self.scheduled_stop_point = Some((id()?, None)),
+            b"Operator" => self.operator = Some((id()?, None)),
+            b"ServiceJourneyPattern" | b"JourneyPattern" => {
+                self.journey_pattern = Some((id()?, Vec::new()))
+            }
+            b"StopPointInJourneyPattern" => {
+                self.stop_point = Some(StopPointInJourneyPattern {
+                    id: id()?,
+                    order: attribute(element, "order")?
+                        .and_then(|order| order.parse().ok())
+                        .unwrap_or_default(),
+                    ..Default::default()
+                })
+            }
+            b"ScheduledStopPointRef" => {
+                if let Some(stop_point) = &mut self.stop_point {
+                    stop_point.scheduled_stop_point = reference()?;
+                }
+            }
+            b"ServiceJourney" => {
+                self.service_journey = Some(ServiceJourney {
+                    id: id()?,
+                    ..Default::default()
+                })
+            }
+            b"TimetabledPassingTime" => self.passing_time = Some(Default::default()),
+            b"StopPointInJourneyPatternRef" => {
+                if let Some(passing_time) = &mut self.passing_time {
+                    passing_time.stop_point_in_journey_pattern = reference()?;
+                }
+            }
+            b"ServiceJourneyPatternRef" | b"JourneyPatternRef" => {
+                if let Some(service_journey) = &mut self.service_journey {
+                    service_journey.journey_pattern = Some(reference()?);
+                }
+            }
+            b"OperatorRef" => {
+                if let Some(service_journey) = &mut self.service_journey {
+                    service_journey.operator = Some(reference()?);
+                }
+            }
+            b"TypeOfProductCategoryRef" => {
+                if let Some(service_journey) = &mut self.service_journey {
+                    service_journey.product_category = Some(id_suffix(&reference()?).to_string());
+                }
+            }
+            b"DayTypeRef" => {
+                if let Some(assignment) = &mut self.day_type_assignment {
+                    assignment.day_type = reference()?;
+                } else if let Some(service_journey) = &mut self.service_journey {
+                    service_journey.day_types.push(reference()?);
+                }
+            }
+            b"UicOperatingPeriod" | b"OperatingPeriod" => {
+                self.operating_period = Some(OperatingPeriod {
+                    id: id()?,
+                    ..Default::default()
+                })
+            }
+            b"DayTypeAssignment" => self.day_type_assignment = Some(Default::default()),
+            b"OperatingPeriodRef" | b"UicOperatingPeriodRef" => {
+                if let Some(assignment) = &mut self.day_type_assignment {
+                    assignment.operating_period = Some(reference()?);
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn text(&mut self, element: &str, text: &str) -> Result<()> {
+        let parent = self.parent().unwrap_or_default().to_string();
+
+        match (parent.as_str(), element) {
+            ("ScheduledStopPoint", "PrivateCode") => {
+                if let Some((_, code)) = &mut self.scheduled_stop_point {
+                    *code = Some(text.to_string());
+                }
+            }
+            ("Operator", "ShortName") => {
+                if let Some((_, name)) = &mut self.operator {
+                    *name = Some(text.to_string());
+                }
+            }
+            ("StopPointInJourneyPattern", "ForAlighting") => {
+                if let Some(stop_point) = &mut self.stop_point {
+                    stop_point.for_alighting = Some(parse_bool(text));
+                }
+            }
+            ("StopPointInJourneyPattern", "ForBoarding") => {
+                if let Some(stop_point) = &mut self.stop_point {
+                    stop_point.for_boarding = Some(parse_bool(text));
+                }
+            }
+            ("ServiceJourney", "PrivateCode") => {
+                if let Some(service_journey) = &mut self.service_journey {
+                    service_journey.private_code = Some(text.to_string());
+                }
+            }
+            ("ServiceJourney", "TransportMode") => {
+                if let Some(service_journey) = &mut self.service_journey {
+                    service_journey.transport_mode = Some(text.to_string());
+                }
+            }
+            ("TimetabledPassingTime", field) => {
+                let Some(passing_time) = &mut self.passing_time else {
+                    return Ok(());
+                };
+
+                match field {
+                    "ArrivalTime" => passing_time.arrival_time = Some(parse_time(text)?),
+                    "DepartureTime" => passing_time.departure_time = Some(parse_time(text)?),
+                    _ => {}
+                }
+            }
+            ("UicOperatingPeriod" | "OperatingPeriod", field) => {
+                let Some(operating_period) = &mut self.operating_period else {
+                    return Ok(());
+                };
+
+                match field {
+                    "FromDate" => operating_period.from_date = Some(parse_date(text)?),
+                    "ValidDayBits" => operating_period.valid_day_bits = Some(text.to_string()),
+                    _ => {}
+                }
+            }
+            ("DayTypeAssignment", field) => {
+                let Some(assignment) = &mut self.day_type_assignment else {
+                    return Ok(());
+                };
+
+                match field {
+                    "Date" => assignment.date = Some(parse_date(text)?),
+                    "isAvailable" => assignment.is_available = Some(parse_bool(text)),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn end(&mut self, element: &str, data: &mut NetexData) {
+        match element {
+            "ScheduledStopPoint" => {
+                if let Some((id, code)) = self.scheduled_stop_point.take() {
+                    let code = code.unwrap_or_else(|| id_suffix(&id).to_string());
+                    data.scheduled_stop_points.insert(id, code.to_lowercase());
+                }
+            }
+            "Operator" => {
+                if let Some((id, name)) = self.operator.take() {
+                    let name = name.unwrap_or_else(|| id_suffix(&id).to_string());
+                    data.operators.insert(id, name);
+                }
+            }
+            "ServiceJourneyPattern" | "JourneyPattern" => {
+                if let Some((id, mut stop_points)) = self.journey_pattern.take() {
+                    stop_points.sort_by_key(|stop_point| stop_point.order);
+                    data.journey_patterns.insert(id, stop_points);
+                }
+            }
+            "StopPointInJourneyPattern" => {
+                if let (Some(stop_point), Some((_, stop_points))) =
+                    (self.stop_point.take(), &mut self.journey_pattern)
+                {
+                    stop_points.push(stop_point);
+                }
+            }
+            "ServiceJourney" => {
+                if let Some(service_journey) = self.service_journey.take() {
+                    data.service_journeys.push(service_journey);
+                }
+            }
+            "TimetabledPassingTime" => {
+                if let (Some(passing_time), Some(service_journey)) =
+                    (self.passing_time.take(), &mut self.service_journey)
+                {
+                    service_journey.passing_times.push(passing_time);
+                }
+            }
+            "UicOperatingPeriod" | "OperatingPeriod" => {
+                if let Some(operating_period) = self.operating_period.take() {
+                    data.operating_periods
+                        .insert(operating_period.id.clone(), operating_period);
+                }
+            }
+            "DayTypeAssignment" => {
+                if let Some(assignment) = self.day_type_assignment.take() {
+                    data.day_type_assignments.push(assignment);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Reads a NeTEx document event by event, so publications that don't fit in memory as a DOM can
+/// still be read. Only the elements needed for journeys are kept.
+fn read_netex<R: BufRead>(reader: R, data: &mut NetexData) -> Result<()> {
+    let mut reader = Reader::from_reader(reader);
+    reader.config_mut().trim_text(true);
+
+    let mut state = ParserState::default();
+    let mut buf = Vec::new();
+
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(element) => {
+                state
+                    .path
+                    .push(String::from_utf8_lossy(element.local_name().as_ref()).into());
+                state.text.clear();
+                state.start(&element)?;
+            }
+            Event::Empty(element) => {
+                state.start(&element)?;
+                let name = String::from_utf8_lossy(element.local_name().as_ref()).into_owned();
+                state.end(&name, data);
+            }
+            Event::Text(text) => state.text.push_str(&text.decode()?),
+            Event::CData(text) => state.text.push_str(&text.decode()?),
+            Event::GeneralRef(reference) => {
+                let name = reference.decode()?;
+                let resolved = resolve_predefined_entity(&name)
+                    .ok_or_else(|| anyhow!("! unknown entity &{name};"))?;
+                state.text.push_str(resolved);
+            }
+            Event::End(_) => {
+                let name = state.path.last().cloned().unwrap_or_default();
+                let text = std::mem::take(&mut state.text);
+                if !text.is_empty() {
+                    state.text(&name, text.trim())?;
+                }
+                state.end(&name, data);
+                state.path.pop();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+
+        buf.clear();
+    }
+
+    Ok(())
+}
+
+/// Dates per DayType, from either explicit dates or the valid day bits of an operating period.
+fn day_type_dates(data: &NetexData) -> HashMap<&str, BTreeSet<NaiveDate>> {
+    let mut dates: HashMap<&str, BTreeSet<NaiveDate>> = HashMap::new();
+
+    let (available, unavailable): (Vec<_>, Vec<_>) = data
+        .day_type_assignments
+        .iter()
+        .partition(|assignment| assignment.is_available.unwrap_or(true));
+
+    for assignment in available {
+        let day_type_dates = dates.entry(assignment.day_type.as_str()).or_default();
+        day_type_dates.extend(assignment.date);
+
+        let Some(operating_period) = assignment
+            .operating_period
+            .as_ref()
+            .and_then(|id| data.operating_periods.get(id))
+        else {
+            continue;
+        };
+
+        if let (Some(from_date), Some(bits)) =
+            (operating_period.from_date, &operating_period.valid_day_bits)
+        {
+            day_type_dates.extend(
+                bits.chars()
+                    .zip(from_date.iter_days())
+                    .filter(|(bit, _)| *bit == '1')
+                    .map(|(_, date)| date),
+            );
+        }
+    }
+
+    for assignment in unavailable {
+        if let (Some(day_type_dates), Some(date)) =
+            (dates.get_mut(assignment.day_type.as_str()), assignment.date)
+        {
+            day_type_dates.remove(&date);
+        }
+    }
+
+    dates
+}
+
+fn build_journeys(data: &NetexData) -> Vec<ImportedJourney> {
+    let day_type_dates = day_type_dates(data);
+    let mut journeys = Vec::new();
+
+    for service_journey in &data.service_journeys {
+        let Some(train_number) = service_journey.private_code.clone() else {
+            continue;
+        };
+
+        let running_on = service_journey
+            .day_types
+            .iter()
+            .filter_map(|day_type| day_type_dates.get(day_type.as_str()))
+            .flatten()
+            .copied()
+            .collect::<BTreeSet<_>>();
+        if running_on.is_empty() {
+            continue;
+        }
+
+        let Some(stop_points) = service_journey
+            .journey_pattern
+            .as_ref()
+            .and_then(|id| data.journey_patterns.get(id))
+        else {
+            continue;
+        };
+
+        let mut events = service_journey
+            .passing_times
+            .iter()
+            .filter_map(|passing_time| {
+                let stop_point = stop_points.iter().find(|stop_point| {
+                    stop_point.id == passing_time.stop_point_in_journey_pattern
+                })?;
+                let station = data
+                    .scheduled_stop_points
+                    .get(&stop_point.scheduled_stop_point)?;
+
+                let stops = stop_point.for_alighting != Some(false)
+                    || stop_point.for_boarding != Some(false);
+
+                Some((stop_point.order, station, stops, passing_time))
+            })
+            .collect::<Vec<_>>();
+        events.sort_by_key(|(order, ..)| *order);
+
+        if events.len() < 2 {
+            continue;
+        }
+
+        let last = events.len() - 1;
+        let events = events
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (_, station, stops, passing_time))| {
+                let event_type = match idx {
+                    0 => StationEventType::Departure,
+                    idx if idx == last => StationEventType::Arrival,
+                    _ if !stops => StationEventType::Passage,
+                    _ => StationEventType::ShortStop,
+                };
+
+                ImportedJourneyEvent {
+                    station: station.clone(),
+                    arrival_time: (idx != 0)
+                        .then_some(passing_time.arrival_time.or(passing_time.departure_time))
+                        .flatten(),
+                    departure_time: (idx != last)
+                        .then_some(passing_time.departure_time.or(passing_time.arrival_time))
+                        .flatten(),
+                    event_type,
+                    platform: None,
+                }
+            })
+            .collect();
+
+        journeys.push(ImportedJourney {
+            source_id: format!("netex:{}", service_journey.id),
+            train_number,
+            service_type: service_journey
+                .product_category
+                .clone()
+                .or_else(|| service_journey.transport_mode.clone())
+                .unwrap_or_else(|| "rail".to_string()),
+            provider: service_journey
+                .operator
+                .as_ref()
+                .map(|id| {
+                    data.operators
+                        .get(id)
+                        .cloned()
+                        .unwrap_or_else(|| id_suffix(id).to_string())
+                })
+                .unwrap_or_default(),
+            running_on: running_on.into_iter().collect(),
+            events,
+        });
+    }
+
+    journeys
+}
+
+fn netex_files(input_path: &Path) -> Result<Vec<PathBuf>> {
+    if input_path.is_file() {
+        return Ok(vec![input_path.to_path_buf()]);
+    }
+
+    let mut files = fs::read_dir(input_path)
+        .context("! failed to read input path")?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.retain(|path| path.extension().is_some_and(|extension| extension == "xml"));
+    files.sort();
+
+    Ok(files)
+}
+
+/// Imports the ServiceJourneys in the NeTEx file at `input_path`, or in all XML files in it if it
+/// is a directory, as services, journeys and journey events.
+pub async fn import(
+    db: Arc<Pool>,
+    input_path: String,
+    options: WorkerOptions,
+) -> Result<ImportSummary> {
+    info!(logger(), "Using input path"; "path" => &input_path);
+
+    let mut data = NetexData::default();
+    for path in netex_files(&PathBuf::from(input_path))? {
+        info!(logger(), "Reading NeTEx file"; "path" => path.display().to_string());
+        let file = File::open(&path).context("! failed to open NeTEx file")?;
+        read_netex(BufReader::new(file), &mut data)
+            .context(format!("! failed to parse {}", path.display()))?;
+    }
+
+    info!(logger(), "Loaded NeTEx data";
+        "scheduled_stop_points" => data.scheduled_stop_points.len(),
+        "service_journeys" => data.service_journeys.len(),
+    );
+
+    let journeys = build_journeys(&data);
+    info!(logger(), "Importing journeys"; "journeys" => journeys.len());
+
+    store_journeys(db, journeys, options).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_builds_journeys_from_sample() {
+        let file = File::open("./fixtures/netex/sample.xml").expect("failed to open sample");
+        let mut data = NetexData::default();
+        read_netex(BufReader::new(file), &mut data).expect("failed to parse sample");
+
+        assert_eq!(data.scheduled_stop_points.len(), 4);
+        assert_eq!(data.service_journeys.len(), 2);
+
+        let journeys = build_journeys(&data);
+        let time = |h, m| NaiveTime::from_hms_opt(h, m, 0);
+        let date = |day| NaiveDate::from_ymd_opt(2025, 6, day).unwrap();
+
+        assert_eq!(journeys.len(), 1);
+        assert_eq!(
+            journeys[0],
+            ImportedJourney {
+                source_id: "netex:NS:ServiceJourney:4084".to_string(),
+                train_number: "4084".to_string(),
+                service_type: "SPR".to_string(),
+                provider: "NS".to_string(),
+                // valid day bits for the 2nd to the 4th, with the 3rd removed again
+                running_on: vec![date(2), date(4)],
+                events: vec![
+                    ImportedJourneyEvent {
+                        station: "gd".to_string(),
+                        event_type: StationEventType::Departure,
+                        arrival_time: None,
+                        departure_time: time(23, 48),
+                        platform: None,
+                    },
+                    ImportedJourneyEvent {
+                        station: "gdg".to_string(),
+                        event_type: StationEventType::Passage,
+                        arrival_time: time(23, 52),
+                        departure_time: time(23, 52),
+                        platform: None,
+                    },
+                    ImportedJourneyEvent {
+                        station: "wd".to_string(),
+                        event_type: StationEventType::ShortStop,
+                        arrival_time: time(23, 59),
+                        departure_time: time(0, 1),
+                        platform: None,
+                    },
+                    ImportedJourneyEvent {
+                        station: "ut".to_string(),
+                        event_type: StationEventType::Arrival,
+                        arrival_time: time(0, 20),
+                        departure_time: None,
+                        platform: None,
+                    },
+                ],
+            }
+        );
+    }
+}
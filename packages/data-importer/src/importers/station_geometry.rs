@@ -1,8 +1,10 @@
-use crate::db::StationGeometry;
-use crate::ns::create_ns_api_client;
+use crate::db::{StationGeometry, postgis};
+use crate::instrumentation::{in_span, metrics, timed};
+use crate::ns::{ResponseSource, load_response};
 use deadpool_postgres::Pool;
-use sea_query::{OnConflict, PostgresQueryBuilder, Query};
+use sea_query::{OnConflict, PostgresQueryBuilder, Query, SimpleExpr};
 use serde::{Deserialize, Serialize};
+use std::path::Path;
 use std::sync::Arc;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -19,14 +21,12 @@ struct Properties {
 
 #[derive(Clone, Debug, Deserialize)]
 struct Feature {
-    r#type: String,
     geometry: LineString,
     properties: Properties,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 struct FeatureCollection {
-    r#type: String,
     features: Vec<Feature>,
 }
 
@@ -35,42 +35,76 @@ struct StationGeometryResponse {
     payload: FeatureCollection,
 }
 
-const API_URL: &str = "https://gateway.apiportal.ns.nl/spoorkaart-api/api/v1/spoorkaart";
+pub(crate) const API_URL: &str = "https://gateway.apiportal.ns.nl/spoorkaart-api/api/v1/spoorkaart";
 
-pub async fn import(db_pool: Arc<Pool>, api_key: &str) -> anyhow::Result<()> {
+pub async fn import(
+    db_pool: Arc<Pool>,
+    source: &ResponseSource,
+    dump: Option<&Path>,
+    postgis: bool,
+) -> anyhow::Result<()> {
+    let response = in_span(
+        "fetch_station_geometry",
+        vec![],
+        load_response(API_URL, source, dump),
+    )
+    .await?;
+    in_span(
+        "import_station_geometry",
+        vec![],
+        import_response(db_pool, &response, postgis),
+    )
+    .await
+}
+
+/// Imports a raw response body of the NS API, also into the PostGIS columns if `postgis` is set.
+pub(crate) async fn import_response(
+    db_pool: Arc<Pool>,
+    response: &str,
+    postgis: bool,
+) -> anyhow::Result<()> {
     let db = db_pool.get().await?;
-    let http_client = create_ns_api_client(api_key)?;
+    if postgis {
+        postgis::check_columns(&db).await?;
+    }
 
-    let response = http_client
-        .get(API_URL)
-        .send()
-        .await?
-        .json::<StationGeometryResponse>()
-        .await?;
+    let response = serde_json::from_str::<StationGeometryResponse>(response)?;
 
     let mut qb = Query::insert();
     qb.into_table(StationGeometry::Table)
-        .columns([
-            StationGeometry::From,
-            StationGeometry::To,
-            StationGeometry::LineString,
-        ])
+        .columns(
+            [
+                StationGeometry::From,
+                StationGeometry::To,
+                StationGeometry::LineString,
+            ]
+            .into_iter()
+            .chain(postgis.then_some(StationGeometry::LineStringGeometry)),
+        )
         .on_conflict(
             OnConflict::columns([StationGeometry::From, StationGeometry::To])
                 .update_column(StationGeometry::LineString)
+                .update_columns(postgis.then_some(StationGeometry::LineStringGeometry))
                 .to_owned(),
         );
 
+    let rows = response.payload.features.len() as u64;
     for feature in response.payload.features {
-        qb.values_panic([
+        let geometry = serde_json::to_string(&feature.geometry)?;
+        let mut values: Vec<SimpleExpr> = vec![
             feature.properties.from.into(),
             feature.properties.to.into(),
-            serde_json::to_string(&feature.geometry)?.into(),
-        ]);
+            geometry.clone().into(),
+        ];
+        if postgis {
+            values.push(postgis::from_geojson(geometry));
+        }
+        qb.values_panic(values);
     }
 
     let sql = qb.to_string(PostgresQueryBuilder);
-    db.batch_execute(&sql).await?;
+    timed("upsert_station_geometry", db.batch_execute(&sql)).await?;
+    metrics().record_rows("station_geometry", rows);
 
     Ok(())
 }
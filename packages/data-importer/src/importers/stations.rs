@@ -1,10 +1,16 @@
-use crate::db;
-use crate::ns::create_ns_api_client;
-use anyhow::Result;
-use deadpool_postgres::Pool;
-use sea_query::{Expr, OnConflict, PostgresQueryBuilder, Query, QueryStatementWriter};
+use crate::db::{self, postgis};
+use crate::instrumentation::{in_span, metrics, timed};
+use crate::logging::logger;
+use crate::ns::{ResponseSource, load_response};
+use anyhow::{Context, Result};
+use deadpool_postgres::{Pool, Transaction};
+use sea_query::{Alias, Expr, OnConflict, PostgresQueryBuilder, Query, SimpleExpr};
+use sea_query_postgres::PostgresBinder;
 use serde::{Deserialize, Serialize};
+use slog::info;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{Display, Formatter};
+use std::path::Path;
 use std::sync::Arc;
 
 #[derive(Debug, Deserialize, Clone)]
@@ -98,44 +104,287 @@ struct StationResponse {
     pub payload: Vec<Station>,
 }
 
-const API_URL: &str = "https://gateway.apiportal.ns.nl/nsapp-stations/v3";
+/// The fields of a station as they are currently stored, to compare new data against.
+#[derive(Debug, Clone, PartialEq)]
+struct StoredStation {
+    code: String,
+    station_type: String,
+    name_long: String,
+    name_medium: Option<String>,
+    name_short: Option<String>,
+    country: String,
+    location: Option<String>,
+    deleted: bool,
+}
 
-pub async fn import(db_pool: Arc<Pool>, api_key: &str) -> Result<()> {
-    let db = db_pool.get().await?;
-    let http_client = create_ns_api_client(api_key)?;
+impl StoredStation {
+    fn fields(&self) -> [(&'static str, Option<String>); 7] {
+        [
+            ("code", Some(self.code.clone())),
+            ("station_type", Some(self.station_type.clone())),
+            ("name_long", Some(self.name_long.clone())),
+            ("name_medium", self.name_medium.clone()),
+            ("name_short", self.name_short.clone()),
+            ("country", Some(self.country.clone())),
+            ("location", self.location.clone()),
+        ]
+    }
+}
 
-    let response = http_client
-        .get(API_URL)
-        .send()
-        .await?
-        .json::<StationResponse>()
-        .await?;
+/// Locations are compared with a precision of about 10 centimetres, to ignore floating point
+/// noise from the round trip through the database.
+fn format_location(lat: f64, lng: f64) -> String {
+    format!("{lat:.6},{lng:.6}")
+}
 
-    let missing_data = include_str!("./stations/missing.json");
-    let missing_data = serde_json::from_str::<StationResponse>(missing_data)?;
+impl From<&Station> for StoredStation {
+    fn from(station: &Station) -> Self {
+        StoredStation {
+            code: station.id.code.to_lowercase(),
+            station_type: station.station_type.to_string(),
+            name_long: station.names.long.clone(),
+            name_medium: Some(station.names.medium.clone()),
+            name_short: Some(station.names.short.clone()),
+            country: station.country.clone(),
+            location: Some(format_location(station.location.lat, station.location.lng)),
+            deleted: false,
+        }
+    }
+}
 
-    let mut qb = Query::insert();
-    qb.into_table(db::Station::Table)
+#[derive(Debug, Clone, PartialEq)]
+struct HistoryEntry {
+    uic_code: String,
+    field: &'static str,
+    old_value: Option<String>,
+    new_value: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct StationChanges {
+    added: Vec<String>,
+    /// Stations that are no longer returned by the API, by UIC code
+    removed: Vec<String>,
+    restored: Vec<String>,
+    history: Vec<HistoryEntry>,
+    /// Station code per UIC code, only used to make the summary readable
+    codes: HashMap<String, String>,
+}
+
+impl StationChanges {
+    fn print_summary(&self) {
+        let code = |uic_code: &String| self.codes.get(uic_code).unwrap_or(uic_code).clone();
+
+        for uic_code in &self.added {
+            info!(logger(), "Added station"; "station" => code(uic_code));
+        }
+        for entry in &self.history {
+            info!(logger(), "Station changed";
+                "station" => code(&entry.uic_code),
+                "field" => entry.field,
+                "old_value" => entry.old_value.as_deref().unwrap_or("-"),
+                "new_value" => entry.new_value.as_deref().unwrap_or("-"),
+            );
+        }
+
+        let changed = self
+            .history
+            .iter()
+            .map(|entry| entry.uic_code.as_str())
+            .collect::<HashSet<_>>();
+        info!(logger(), "Imported stations";
+            "added" => self.added.len(),
+            "changed" => changed.len(),
+            "removed" => self.removed.len(),
+            "restored" => self.restored.len(),
+        );
+    }
+}
+
+/// Compares the stations from the API and `missing.json` with the stored ones. Stored stations
+/// that are in neither are considered removed.
+fn diff_stations(
+    stored: &BTreeMap<String, StoredStation>,
+    from_api: &[Station],
+    missing: &[Station],
+) -> StationChanges {
+    let mut changes = StationChanges::default();
+    let mut seen = HashSet::new();
+
+    for station in from_api.iter().chain(missing.iter()) {
+        let uic_code = station.id.uic.clone();
+        if !seen.insert(uic_code.clone()) {
+            continue;
+        }
+
+        let new = StoredStation::from(station);
+        changes.codes.insert(uic_code.clone(), new.code.clone());
+
+        let Some(old) = stored.get(&uic_code) else {
+            changes.added.push(uic_code);
+            continue;
+        };
+
+        for ((field, old_value), (_, new_value)) in old.fields().into_iter().zip(new.fields()) {
+            if old_value != new_value {
+                changes.history.push(HistoryEntry {
+                    uic_code: uic_code.clone(),
+                    field,
+                    old_value,
+                    new_value,
+                });
+            }
+        }
+
+        if old.deleted {
+            changes.history.push(HistoryEntry {
+                uic_code: uic_code.clone(),
+                field: "deleted",
+                old_value: Some(true.to_string()),
+                new_value: Some(false.to_string()),
+            });
+            changes.restored.push(uic_code);
+        }
+    }
+
+    for (uic_code, station) in stored {
+        if station.deleted || seen.contains(uic_code) {
+            continue;
+        }
+
+        changes.codes.insert(uic_code.clone(), station.code.clone());
+        changes.history.push(HistoryEntry {
+            uic_code: uic_code.clone(),
+            field: "deleted",
+            old_value: Some(false.to_string()),
+            new_value: Some(true.to_string()),
+        });
+        changes.removed.push(uic_code.clone());
+    }
+
+    changes
+}
+
+async fn load_stored_stations(client: &Transaction<'_>) -> Result<BTreeMap<String, StoredStation>> {
+    let (sql, params) = Query::select()
         .columns([
             db::Station::UicCode,
-            db::Station::UicCdCode,
-            db::Station::EvaCode,
-            db::Station::CdCode,
             db::Station::Code,
             db::Station::StationType,
             db::Station::NameLong,
             db::Station::NameMedium,
             db::Station::NameShort,
-            db::Station::NameSynonyms,
             db::Station::Country,
-            db::Station::Tracks,
-            db::Station::HasTravelAssistance,
-            db::Station::IsBorderStop,
-            db::Station::IsAvailableForAccessibleTravel,
-            db::Station::HasKnownFacilities,
-            db::Station::AreTracksIndependentlyAccessible,
-            db::Station::Location,
         ])
+        // stored as point(lat, lng)
+        .expr_as(Expr::cust("location[0]"), Alias::new("lat"))
+        .expr_as(Expr::cust("location[1]"), Alias::new("lng"))
+        .expr_as(
+            Expr::col(db::Station::DeletedAt).is_not_null(),
+            Alias::new("deleted"),
+        )
+        .from(db::Station::Table)
+        .build_postgres(PostgresQueryBuilder);
+
+    let rows = client.query(sql.as_str(), &params.as_params()).await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let lat: Option<f64> = row.get("lat");
+            let lng: Option<f64> = row.get("lng");
+
+            (
+                row.get("uic_code"),
+                StoredStation {
+                    code: row.get("code"),
+                    station_type: row.get("station_type"),
+                    name_long: row.get("name_long"),
+                    name_medium: row.get("name_medium"),
+                    name_short: row.get("name_short"),
+                    country: row.get("country"),
+                    location: lat.zip(lng).map(|(lat, lng)| format_location(lat, lng)),
+                    deleted: row.get("deleted"),
+                },
+            )
+        })
+        .collect())
+}
+
+pub(crate) const API_URL: &str = "https://gateway.apiportal.ns.nl/nsapp-stations/v3";
+
+pub async fn import(
+    db_pool: Arc<Pool>,
+    source: &ResponseSource,
+    dump: Option<&Path>,
+    postgis: bool,
+) -> Result<()> {
+    let response = in_span(
+        "fetch_stations",
+        vec![],
+        load_response(API_URL, source, dump),
+    )
+    .await?;
+    in_span(
+        "import_stations",
+        vec![],
+        import_response(db_pool, &response, postgis),
+    )
+    .await
+}
+
+/// Imports a raw response body of the NS API, also into the PostGIS columns if `postgis` is set.
+pub(crate) async fn import_response(
+    db_pool: Arc<Pool>,
+    response: &str,
+    postgis: bool,
+) -> Result<()> {
+    let mut db = db_pool.get().await?;
+
+    let response = serde_json::from_str::<StationResponse>(response)?;
+
+    let missing_data = include_str!("./stations/missing.json");
+    let missing_data = serde_json::from_str::<StationResponse>(missing_data)?;
+
+    if postgis {
+        postgis::check_columns(&db).await?;
+    }
+
+    let transaction = db
+        .transaction()
+        .await
+        .context("! failed to start transaction")?;
+
+    let stored = load_stored_stations(&transaction).await?;
+    let changes = diff_stations(&stored, &response.payload, &missing_data.payload);
+
+    let mut qb = Query::insert();
+    qb.into_table(db::Station::Table)
+        .columns(
+            [
+                db::Station::UicCode,
+                db::Station::UicCdCode,
+                db::Station::EvaCode,
+                db::Station::CdCode,
+                db::Station::Code,
+                db::Station::StationType,
+                db::Station::NameLong,
+                db::Station::NameMedium,
+                db::Station::NameShort,
+                db::Station::NameSynonyms,
+                db::Station::Country,
+                db::Station::Tracks,
+                db::Station::HasTravelAssistance,
+                db::Station::IsBorderStop,
+                db::Station::IsAvailableForAccessibleTravel,
+                db::Station::HasKnownFacilities,
+                db::Station::AreTracksIndependentlyAccessible,
+                db::Station::Location,
+                db::Station::DeletedAt,
+            ]
+            .into_iter()
+            .chain(postgis.then_some(db::Station::LocationGeometry)),
+        )
         .on_conflict(
             OnConflict::column(db::Station::UicCode)
                 .update_columns([
@@ -156,12 +405,14 @@ pub async fn import(db_pool: Arc<Pool>, api_key: &str) -> Result<()> {
                     db::Station::HasKnownFacilities,
                     db::Station::AreTracksIndependentlyAccessible,
                     db::Station::Location,
+                    db::Station::DeletedAt,
                 ])
+                .update_columns(postgis.then_some(db::Station::LocationGeometry))
                 .to_owned(),
         );
 
     for station in response.payload.iter().chain(missing_data.payload.iter()) {
-        qb.values([
+        let mut values: Vec<SimpleExpr> = vec![
             station.id.uic.clone().into(),
             station.id.uic_cd.clone().into(),
             station.id.eva.clone().into(),
@@ -193,11 +444,124 @@ pub async fn import(db_pool: Arc<Pool>, api_key: &str) -> Result<()> {
                 "point({}, {})",
                 station.location.lat, station.location.lng
             )),
-        ])?;
+            Expr::cust("NULL"),
+        ];
+        if postgis {
+            values.push(postgis::point(station.location.lat, station.location.lng));
+        }
+        qb.values(values)?;
     }
 
     let sql = qb.to_string(PostgresQueryBuilder);
-    db.batch_execute(&sql).await?;
+    timed("upsert_stations", transaction.batch_execute(&sql))
+        .await
+        .context("! failed to upsert stations")?;
+
+    if !changes.removed.is_empty() {
+        let (sql, params) = Query::update()
+            .table(db::Station::Table)
+            .value(db::Station::DeletedAt, Expr::current_timestamp())
+            .and_where(Expr::col(db::Station::UicCode).is_in(changes.removed.iter().cloned()))
+            .build_postgres(PostgresQueryBuilder);
+
+        transaction
+            .execute(sql.as_str(), &params.as_params())
+            .await
+            .context("! failed to soft-delete stations")?;
+    }
+
+    if !changes.history.is_empty() {
+        let mut history_insert = Query::insert();
+        history_insert
+            .into_table(db::StationHistory::Table)
+            .columns([
+                db::StationHistory::UicCode,
+                db::StationHistory::Field,
+                db::StationHistory::OldValue,
+                db::StationHistory::NewValue,
+            ]);
+
+        for change in &changes.history {
+            history_insert.values_panic([
+                change.uic_code.clone().into(),
+                change.field.into(),
+                change.old_value.clone().into(),
+                change.new_value.clone().into(),
+            ]);
+        }
+
+        let (sql, params) = history_insert.build_postgres(PostgresQueryBuilder);
+        transaction
+            .execute(sql.as_str(), &params.as_params())
+            .await
+            .context("! failed to insert station history")?;
+    }
+
+    transaction
+        .commit()
+        .await
+        .context("! could not commit transaction")?;
+
+    metrics().record_rows(
+        "station",
+        (response.payload.len() + missing_data.payload.len() + changes.removed.len()) as u64,
+    );
+    metrics().record_rows("station_history", changes.history.len() as u64);
+    changes.print_summary();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_detects_station_changes() {
+        let missing =
+            serde_json::from_str::<StationResponse>(include_str!("./stations/missing.json"))
+                .unwrap()
+                .payload;
+        let (from_api, missing) = missing.split_at(2);
+
+        let mut stored = BTreeMap::new();
+
+        // renamed and reclassified
+        let mut aachen = StoredStation::from(&from_api[0]);
+        aachen.name_long = "Aachen-Schanz".to_string();
+        aachen.station_type = "EXPRESS_TRAIN_STATION".to_string();
+        stored.insert(from_api[0].id.uic.clone(), aachen);
+
+        // deleted earlier, but returned by the API again
+        let mut dresden = StoredStation::from(&from_api[1]);
+        dresden.deleted = true;
+        stored.insert(from_api[1].id.uic.clone(), dresden);
+
+        // no longer returned by the API
+        let mut removed = StoredStation::from(&from_api[0]);
+        removed.code = "xyz".to_string();
+        stored.insert("8400000".to_string(), removed);
+
+        // unchanged, and kept because it's in missing.json
+        stored.insert(missing[0].id.uic.clone(), StoredStation::from(&missing[0]));
+
+        let changes = diff_stations(&stored, from_api, missing);
+
+        assert_eq!(changes.added.len(), missing.len() - 1);
+        assert_eq!(changes.removed, vec!["8400000".to_string()]);
+        assert_eq!(changes.restored, vec![from_api[1].id.uic.clone()]);
+        assert_eq!(
+            changes
+                .history
+                .iter()
+                .map(|entry| (entry.uic_code.as_str(), entry.field))
+                .collect::<Vec<_>>(),
+            vec![
+                ("8031372", "station_type"),
+                ("8031372", "name_long"),
+                ("8006050", "deleted"),
+                ("8400000", "deleted"),
+            ]
+        );
+    }
+}
@@ -1,50 +1,219 @@
+pub mod cache;
+pub mod diff;
+pub(crate) mod distances;
+pub mod materialize;
 pub mod parsers;
-
-use crate::db;
+pub mod planner;
+pub mod quality;
+pub mod route_shapes;
+pub mod transfers;
+pub mod validate;
+
+use crate::db::{self, retry_transient};
+use crate::importers::timetable::cache::DeliveryCache;
+use crate::importers::timetable::distances::{SegmentStats, segment_stats, total_distance};
+use crate::importers::timetable::parsers::change::Changes;
 use crate::importers::timetable::parsers::company::Companies;
 use crate::importers::timetable::parsers::footnote::{Footnote, Footnotes};
-use crate::importers::timetable::parsers::service::{Service, ServiceLeg};
+use crate::importers::timetable::parsers::service::ServiceLeg;
+use crate::importers::timetable::parsers::station::Stations;
 use crate::importers::timetable::parsers::timetable::Timetable;
 use crate::importers::timetable::parsers::{
-    company::company_file, footnote::footnote_file, identification::DeliveryIdentified,
-    timetable::timetable_file,
+    change::change_file, company::company_file, footnote::footnote_file,
+    identification::DeliveryIdentified, station::station_file, timetable::timetable_file,
 };
-use crate::util::read_iso_8859_1_file;
+use crate::importers::timetable::route_shapes::RouteShapes;
+use crate::instrumentation::{in_span, metrics, timed};
+use crate::logging::logger;
+use crate::progress::{ImportSummary, PROGRESS_INTERVAL, Progress};
+use crate::util::{decode_iso_8859_1, read_iso_8859_1_file};
 use anyhow::{Context, Result, anyhow};
-use chrono::Utc;
+use chrono::NaiveDate;
 use deadpool_postgres::Pool;
-use nom::{IResult, Parser};
+use nom::IResult;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::{Context as TraceContext, KeyValue};
 use sea_query::{Expr, OnConflict, PostgresQueryBuilder, Query};
 use sea_query_postgres::PostgresBinder;
-use std::io::Cursor;
+use slog::{debug, error, info};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::{env, fs};
+use std::time::Duration;
 use uuid::Uuid;
+use zip::ZipArchive;
+
+/// Where the `.dat` files of a delivery are read from: an extracted directory, or straight from
+/// the zip it came in.
+enum DeliveryFiles {
+    Directory(PathBuf),
+    Archive(ZipArchive<File>),
+}
+
+impl DeliveryFiles {
+    fn open(path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            return Ok(DeliveryFiles::Directory(path.to_path_buf()));
+        }
+
+        let file = File::open(path).context("! failed to open input path")?;
+        let archive = ZipArchive::new(file).context("! input path is not a directory or zip")?;
+
+        Ok(DeliveryFiles::Archive(archive))
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        match self {
+            DeliveryFiles::Directory(dir) => dir.join(name).is_file(),
+            DeliveryFiles::Archive(archive) => archive
+                .file_names()
+                .any(|member| member.rsplit('/').next() == Some(name)),
+        }
+    }
+
+    fn read(&mut self, name: &str) -> Result<String> {
+        match self {
+            DeliveryFiles::Directory(dir) => read_iso_8859_1_file(dir.join(name).to_str().unwrap()),
+            DeliveryFiles::Archive(archive) => {
+                // the files may be in a directory inside of the zip
+                let member = archive
+                    .file_names()
+                    .find(|member| member.rsplit('/').next() == Some(name))
+                    .map(str::to_string)
+                    .context(format!("! {name} not found in zip"))?;
+
+                let mut contents = Vec::new();
+                archive.by_name(&member)?.read_to_end(&mut contents)?;
+
+                decode_iso_8859_1(&contents)
+            }
+        }
+    }
+}
 
 fn load_file<TData>(
-    path: &Path,
+    files: &mut DeliveryFiles,
+    name: &str,
     parser: impl Fn(&str) -> IResult<&str, DeliveryIdentified<TData>>,
 ) -> Result<DeliveryIdentified<TData>> {
-    let file_contents = read_iso_8859_1_file(path.to_str().unwrap())?;
+    let file_contents = files.read(name)?;
     let (_, data) = parser(&file_contents).or(Err(anyhow!("! failed to parse file")))?;
 
     Ok(data)
 }
 
-const DATA_URL: &str = "https://data.ndovloket.nl/ns/ns-latest.zip";
-async fn download_and_extract_data(dir: &Path) -> Result<()> {
-    let timetable_data_archive = reqwest::get(DATA_URL).await?.bytes().await?.to_vec();
+pub(crate) const DATA_URL: &str = "https://data.ndovloket.nl/ns/ns-latest.zip";
 
-    zip_extract::extract(Cursor::new(timetable_data_archive), dir, true)
-        .context("! failed to extract zip")?;
+enum ProcessingResult {
+    Success,
+    Skipped,
+}
 
-    Ok(())
+/// The parsed files of a single IFF delivery.
+pub struct Delivery {
+    pub timetable: Arc<Timetable>,
+    pub footnotes: Arc<Footnotes>,
+    pub companies: Arc<Companies>,
+    pub stations: Arc<Stations>,
+    pub changes: Arc<Changes>,
 }
 
-enum ProcessingResult {
-    Success(u32),
-    Skipped(u32),
+/// Loads only company.dat of the delivery in `input_path`, which is either an extracted directory
+/// or a zip.
+pub(crate) fn load_companies(input_path: &Path) -> Result<Companies> {
+    let mut files = DeliveryFiles::open(input_path)?;
+    load_file(&mut files, "company.dat", company_file).context("! failed to load company.dat")
+}
+
+/// Loads the delivery in `input_path`, which is either an extracted directory or a zip.
+pub(crate) fn load_delivery(input_path: &Path) -> Result<Delivery> {
+    let mut files = DeliveryFiles::open(input_path)?;
+
+    let timetable = load_file(&mut files, "timetbls.dat", timetable_file)
+        .context("! failed to load timetbls.dat")?;
+    let footnotes = load_file(&mut files, "footnote.dat", footnote_file)
+        .context("! failed to load footnote.dat")?;
+    let companies = load_file(&mut files, "company.dat", company_file)
+        .context("! failed to load company.dat")?;
+    let stations = load_file(&mut files, "stations.dat", station_file)
+        .context("! failed to load stations.dat")?;
+    // only needed for transfers between specific services, which a delivery may not have
+    let changes = match files.contains("changes.dat") {
+        true => load_file(&mut files, "changes.dat", change_file)
+            .context("! failed to load changes.dat")?,
+        false => Changes {
+            identification: timetable.identification.clone(),
+            data: Vec::new(),
+        },
+    };
+
+    Ok(Delivery {
+        timetable: Arc::new(timetable),
+        footnotes: Arc::new(footnotes),
+        companies: Arc::new(companies),
+        stations: Arc::new(stations),
+        changes: Arc::new(changes),
+    })
+}
+
+impl Delivery {
+    /// Dates on which services with the given validity footnote run, or `None` if the delivery
+    /// does not contain that footnote.
+    pub fn running_dates(&self, footnote: u32) -> Option<Vec<NaiveDate>> {
+        let always_valid;
+        let footnote = if footnote == 0 {
+            always_valid = Footnote::always_valid(&self.timetable.identification);
+            &always_valid
+        } else {
+            self.footnotes.get_by_id(footnote)?
+        };
+
+        Some(
+            footnote
+                .iterate_valid_dates(&self.timetable.identification)
+                .flatten()
+                .collect(),
+        )
+    }
+}
+
+/// Inclusive range of running dates for which journeys should be created.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct DateWindow {
+    pub first: NaiveDate,
+    pub last: NaiveDate,
+}
+
+impl DateWindow {
+    pub fn contains(&self, date: &NaiveDate) -> bool {
+        self.first <= *date && *date <= self.last
+    }
+}
+
+/// How services are spread over workers that each write to the database through their own
+/// connection.
+#[derive(Debug, Clone, Copy)]
+pub struct WorkerOptions {
+    pub workers: usize,
+    /// Services that can be waiting for a worker, before parsing more services has to wait
+    pub queue_capacity: usize,
+    /// A service that takes longer than this is rolled back and counted as failed
+    pub service_timeout: Duration,
+    /// Retries for a service that failed on a transient database error
+    pub max_retries: u32,
+}
+
+impl Default for WorkerOptions {
+    fn default() -> Self {
+        WorkerOptions {
+            workers: 5,
+            queue_capacity: 100,
+            service_timeout: Duration::from_secs(300),
+            max_retries: 3,
+        }
+    }
 }
 
 struct JourneyProcessingJob {
@@ -53,18 +222,20 @@ struct JourneyProcessingJob {
     timetable: Arc<Timetable>,
     footnotes: Arc<Footnotes>,
     companies: Arc<Companies>,
+    window: Option<DateWindow>,
+    route_shapes: Arc<RouteShapes>,
+    /// Trace context of the delivery this job is part of, as jobs are processed on other tasks
+    trace_context: TraceContext,
 }
 
 impl JourneyProcessingJob {
-    pub(crate) async fn process(self, worker_id: usize) -> Result<ProcessingResult> {
-        println!(
-            "+ Processing service {} with worker {worker_id}",
-            self.service.service_identification.0
+    pub(crate) async fn process(&self, worker_id: usize) -> Result<ProcessingResult> {
+        debug!(logger(), "Processing service";
+            "service" => self.service.service_identification.0,
+            "worker" => worker_id,
         );
 
-        let mut db = self
-            .db
-            .get()
+        let mut db = timed("get_client", self.db.get())
             .await
             .context("worker failed to get client from pool")?;
 
@@ -81,12 +252,10 @@ impl JourneyProcessingJob {
                 .context("! footnote not found")?
         };
 
-        let service_number = match self.get_service_number() {
+        let service_number = match self.service.train_number() {
             Some(service_number) => service_number,
             None => {
-                return Ok(ProcessingResult::Skipped(
-                    self.service.service_identification.0,
-                ));
+                return Ok(ProcessingResult::Skipped);
             }
         };
 
@@ -117,10 +286,12 @@ impl JourneyProcessingJob {
             .returning(Query::returning().column(db::Service::Id))
             .build_postgres(PostgresQueryBuilder);
 
-        let inserted_service = transaction
-            .query(service_sql.as_str(), &service_params.as_params())
-            .await
-            .context("! failed to insert service(s)")?;
+        let inserted_service = timed(
+            "insert_service",
+            transaction.query(service_sql.as_str(), &service_params.as_params()),
+        )
+        .await
+        .context("! failed to insert service(s)")?;
 
         assert_eq!(inserted_service.len(), 1);
 
@@ -144,6 +315,8 @@ impl JourneyProcessingJob {
                 db::JourneyEvent::DepartureTimePlanned,
                 db::JourneyEvent::DeparturePlatformPlanned,
                 db::JourneyEvent::Attributes,
+                db::JourneyEvent::DistancePlanned,
+                db::JourneyEvent::SpeedPlanned,
             ])
             .on_conflict(
                 OnConflict::columns([db::JourneyEvent::JourneyId, db::JourneyEvent::StopOrder])
@@ -155,15 +328,31 @@ impl JourneyProcessingJob {
                         db::JourneyEvent::DepartureTimePlanned,
                         db::JourneyEvent::DeparturePlatformPlanned,
                         db::JourneyEvent::Attributes,
+                        db::JourneyEvent::DistancePlanned,
+                        db::JourneyEvent::SpeedPlanned,
                     ])
                     .to_owned(),
             );
 
-        let mut at_least_one_journey_event = false;
+        let route_shape = self
+            .route_shapes
+            .get(&route_shapes::stop_pattern(&self.service));
+        let times = self.service.event_minutes();
+        let (segment_stats, distance) = match route_shape {
+            Some(route_shape) => (
+                segment_stats(&times, &route_shape.distances),
+                total_distance(&route_shape.distances),
+            ),
+            None => (vec![SegmentStats::default(); times.len()], None),
+        };
+
+        let mut journeys_written = 0;
+        let mut journey_events_written = 0;
 
         for journey in footnote
             .iterate_valid_dates(&self.timetable.identification)
             .flatten()
+            .filter(|date| self.window.is_none_or(|window| window.contains(date)))
         {
             let mut journey_insert = Query::insert();
             let (journey_insert_sql, journey_insert_params) = journey_insert
@@ -173,6 +362,8 @@ impl JourneyProcessingJob {
                     db::Journey::RunningOn,
                     db::Journey::Attributes,
                     db::Journey::SourceIds,
+                    db::Journey::RouteShapeId,
+                    db::Journey::DistancePlanned,
                 ])
                 .values_panic([
                     service_id.into(),
@@ -186,10 +377,16 @@ impl JourneyProcessingJob {
                         })
                         .into(),
                     vec![self.service.service_identification.0.to_string()].into(),
+                    route_shape.map(|route_shape| route_shape.id).into(),
+                    distance.map(|distance| distance.round() as i32).into(),
                 ])
                 .on_conflict(
                     OnConflict::columns([db::Journey::ServiceId, db::Journey::RunningOn])
-                        .update_columns([db::Journey::Attributes])
+                        .update_columns([
+                            db::Journey::Attributes,
+                            db::Journey::RouteShapeId,
+                            db::Journey::DistancePlanned,
+                        ])
                         .value(
                             db::Journey::SourceIds,
                             Expr::cust("ARRAY(SELECT DISTINCT unnest(array_cat(\"journey\".\"source_ids\", \"excluded\".\"source_ids\")))"),
@@ -199,25 +396,28 @@ impl JourneyProcessingJob {
                 .returning(Query::returning().columns([db::Journey::Id]))
                 .build_postgres(PostgresQueryBuilder);
 
-            let inserted_journey = transaction
-                .query(
+            let inserted_journey = timed(
+                "insert_journey",
+                transaction.query(
                     journey_insert_sql.as_str(),
                     &journey_insert_params.as_params(),
-                )
-                .await
-                .context(format!(
-                    "query: {} - params: {:?}",
-                    journey_insert_sql.as_str(),
-                    journey_insert_params.as_params()
-                ))
-                .context("! failed to insert journey")?;
+                ),
+            )
+            .await
+            .context(format!(
+                "query: {} - params: {:?}",
+                journey_insert_sql.as_str(),
+                journey_insert_params.as_params()
+            ))
+            .context("! failed to insert journey")?;
 
             assert_eq!(inserted_journey.len(), 1);
 
             let journey_id: Uuid = inserted_journey.first().unwrap().get("id");
+            journeys_written += 1;
 
             for (idx, (event, platform)) in self.service.station_events.iter().enumerate() {
-                at_least_one_journey_event = true;
+                journey_events_written += 1;
 
                 let stop_attributes = self.service.stop_number(event).and_then(|stop_number| {
                     let attribute_codes = stop_attributes
@@ -231,14 +431,6 @@ impl JourneyProcessingJob {
                     (!attribute_codes.is_empty()).then_some(attribute_codes)
                 });
 
-                let (date, time) = (
-                    journey,
-                    event
-                        .arrival_time
-                        .or(event.departure_time)
-                        .unwrap_or(Utc::now().naive_utc().time()),
-                );
-
                 journey_event_insert.values_panic([
                     journey_id.into(),
                     event.station.clone().into(),
@@ -257,130 +449,419 @@ impl JourneyProcessingJob {
                         None::<String>.into()
                     },
                     stop_attributes.into(),
+                    segment_stats[idx]
+                        .distance
+                        .map(|distance| distance.round() as i32)
+                        .into(),
+                    segment_stats[idx].speed.into(),
                 ]);
             }
         }
 
-        if at_least_one_journey_event {
+        if journey_events_written > 0 {
             let journey_event_insert_query = journey_event_insert.to_string(PostgresQueryBuilder);
-            transaction
-                .batch_execute(journey_event_insert_query.as_str())
-                .await
-                .context(format!("query: {journey_event_insert_query}"))
-                .context("! could not insert journey events")?;
+            timed(
+                "insert_journey_events",
+                transaction.batch_execute(journey_event_insert_query.as_str()),
+            )
+            .await
+            .context(format!("query: {journey_event_insert_query}"))
+            .context("! could not insert journey events")?;
         }
 
-        transaction
-            .commit()
+        timed("commit", transaction.commit())
             .await
             .context("! could not commit transaction")?;
 
-        Ok(ProcessingResult::Success(
-            self.service.service_identification.0,
-        ))
-    }
+        metrics().record_rows("service", 1);
+        metrics().record_rows("journey", journeys_written);
+        metrics().record_rows("journey_event", journey_events_written);
 
-    fn get_service_number(&self) -> Option<String> {
-        (self.service.service_number.service_number != 0)
-            .then_some(self.service.service_number.service_number.to_string())
-            .or(self.service.service_number.variant.clone())
+        Ok(ProcessingResult::Success)
     }
 }
 
 async fn worker(
     id: usize,
     job_rx: async_channel::Receiver<JourneyProcessingJob>,
-    result_tx: async_channel::Sender<Result<ProcessingResult>>,
+    result_tx: async_channel::Sender<(u32, Result<ProcessingResult>)>,
+    options: WorkerOptions,
 ) {
-    println!("+ Worker {id} started");
+    debug!(logger(), "Worker started"; "worker" => id);
     while let Ok(job) = job_rx.recv().await {
-        let output = job.process(id).await;
-        let _ = result_tx.send(output).await;
+        let service = job.service.service_identification.0;
+        let trace_context = job.trace_context.clone();
+        let attributes = vec![
+            KeyValue::new("service", service as i64),
+            KeyValue::new("worker", id as i64),
+        ];
+        let process = retry_transient(format!("service {service}"), options.max_retries, || {
+            let process = tokio::time::timeout(options.service_timeout, job.process(id));
+            async move {
+                process.await.unwrap_or_else(|_| {
+                    Err(anyhow!(
+                        "! timed out after {}s",
+                        options.service_timeout.as_secs()
+                    ))
+                })
+            }
+        });
+        let output = in_span("process_service", attributes, process)
+            .with_context(trace_context)
+            .await;
+        let _ = result_tx.send((service, output)).await;
     }
-    println!("+ Worker {id} exiting");
+    debug!(logger(), "Worker exiting"; "worker" => id);
 }
 
-async fn collect_results(mut rx: async_channel::Receiver<Result<ProcessingResult>>) {
-    while let Ok(result) = rx.recv().await {
-        match result {
-            Ok(result) => match result {
-                ProcessingResult::Success(service_number) => {
-                    println!("+ Service {service_number} processed successfully")
-                }
-                ProcessingResult::Skipped(service_number) => {
-                    println!("+ Service {service_number} skipped")
-                }
+async fn collect_results(
+    rx: async_channel::Receiver<(u32, Result<ProcessingResult>)>,
+    total: usize,
+) -> ImportSummary {
+    let mut progress = Progress::new("services", total);
+    let mut report = tokio::time::interval_at(
+        tokio::time::Instant::now() + PROGRESS_INTERVAL,
+        PROGRESS_INTERVAL,
+    );
+
+    loop {
+        let (service, result) = tokio::select! {
+            received = rx.recv() => match received {
+                Ok(received) => received,
+                Err(_) => break,
             },
+            _ = report.tick() => {
+                progress.log();
+                continue;
+            }
+        };
+
+        match result {
+            Ok(ProcessingResult::Success) => {
+                metrics().record_service("processed");
+                progress.processed();
+                debug!(logger(), "Service processed"; "service" => service);
+            }
+            Ok(ProcessingResult::Skipped) => {
+                metrics().record_service("skipped");
+                progress.skipped();
+                debug!(logger(), "Service skipped"; "service" => service);
+            }
             Err(e) => {
-                println!("! Failed to process service: {e}")
+                metrics().record_service("failed");
+                error!(logger(), "Failed to process service";
+                    "service" => service,
+                    "error" => format!("{e:#}"),
+                );
+                progress.failed(service, &e);
             }
         }
     }
+
+    progress.finish()
 }
 
-pub async fn import(db: Arc<Pool>, input_path: Option<String>) -> Result<()> {
+/// Imports the delivery at `input_path`, or the latest one from NDOV Loket if no path is given.
+pub async fn import(
+    db: Arc<Pool>,
+    input_path: Option<String>,
+    cache: DeliveryCache,
+    options: WorkerOptions,
+    only_services: Option<HashSet<u32>>,
+) -> Result<ImportSummary> {
     let data_dir: PathBuf = if let Some(input_path) = input_path {
-        println!("+ Using input path: {}", input_path);
+        info!(logger(), "Using input path"; "path" => &input_path);
         PathBuf::from(input_path)
     } else {
-        println!("+ Downloading and unzipping latest data");
-        let data_dir = env::temp_dir().join(format!("kedeng-data-importer-{}", Uuid::new_v4()));
-        fs::create_dir_all(&data_dir).context("! failed to create temp dir")?;
-        println!("+ Created temp dir: {}", data_dir.display());
-
-        download_and_extract_data(&data_dir).await?;
+        info!(logger(), "Fetching latest data");
+        in_span("fetch_delivery", vec![], cache.fetch(DATA_URL)).await?
+    };
 
-        data_dir
+    let mut delivery = in_span("load_delivery", vec![], async { load_delivery(&data_dir) }).await?;
+    let Some(only_services) = only_services else {
+        return process_delivery(db, &delivery, None, options).await;
     };
 
-    let timetable = load_file(&data_dir.join("./timetbls.dat"), timetable_file)?;
-    println!("+ Loaded {} services", timetable.data.len());
-    let timetable = Arc::new(timetable);
-    // println!("{:#?}", timetable);
-    // return Ok(());
+    delivery.timetable = Arc::new(filter_services(&delivery.timetable, &only_services));
+    info!(logger(), "Only importing selected services";
+        "selected" => only_services.len(),
+        "found" => delivery.timetable.data.len(),
+    );
+
+    // transfers are replaced as a whole, which would leave out those of all other services
+    in_span(
+        "process_services",
+        vec![],
+        process_services(db, &delivery, None, options),
+    )
+    .await
+}
 
-    let footnotes = load_file(&data_dir.join("./footnote.dat"), footnote_file)?;
-    println!("+ Loaded {} footnotes", footnotes.data.len());
-    let footnotes = Arc::new(footnotes);
+/// Reads service identifications, one per line, like the failed services file written after an
+/// import. Empty lines are ignored.
+pub fn read_service_ids(path: &Path) -> Result<HashSet<u32>> {
+    let content = std::fs::read_to_string(path).context("! failed to read services file")?;
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            line.parse()
+                .map_err(|_| anyhow!("! invalid service identification '{line}'"))
+        })
+        .collect()
+}
 
-    let companies = load_file(&data_dir.join("./company.dat"), company_file)?;
-    println!("+ Loaded {} companies", companies.data.len());
-    let companies = Arc::new(companies);
+fn filter_services(timetable: &Timetable, only_services: &HashSet<u32>) -> Timetable {
+    DeliveryIdentified {
+        identification: timetable.identification.clone(),
+        data: timetable
+            .data
+            .iter()
+            .filter(|service| only_services.contains(&service.identification.0))
+            .cloned()
+            .collect(),
+    }
+}
 
-    let (job_tx, job_rx) = async_channel::unbounded::<JourneyProcessingJob>();
-    let (result_tx, result_rx) = async_channel::unbounded::<Result<ProcessingResult>>();
+/// Stores the transfers of the delivery, and creates services, journeys and journey events for
+/// every service leg in it. If a window is given, only journeys running on a date inside of it are
+/// created.
+pub(crate) async fn process_delivery(
+    db: Arc<Pool>,
+    delivery: &Delivery,
+    window: Option<DateWindow>,
+    options: WorkerOptions,
+) -> Result<ImportSummary> {
+    let attributes = vec![KeyValue::new(
+        "version",
+        delivery.timetable.identification.version_number.clone(),
+    )];
+
+    in_span("process_delivery", attributes, async {
+        in_span(
+            "store_transfers",
+            vec![],
+            transfers::store_transfers(&db, delivery),
+        )
+        .await?;
+
+        process_services(db, delivery, window, options).await
+    })
+    .await
+}
 
-    let worker_handles = (0..5)
-        .map(|id| tokio::spawn(worker(id, job_rx.clone(), result_tx.clone())))
+async fn process_services(
+    db: Arc<Pool>,
+    delivery: &Delivery,
+    window: Option<DateWindow>,
+    options: WorkerOptions,
+) -> Result<ImportSummary> {
+    info!(logger(), "Loaded delivery";
+        "services" => delivery.timetable.data.len(),
+        "footnotes" => delivery.footnotes.data.len(),
+        "companies" => delivery.companies.data.len(),
+    );
+
+    // a service is split into a leg for every service number it runs under, or fails as a whole
+    let total = delivery
+        .timetable
+        .data
+        .iter()
+        .map(|service| service.split_legs().map_or(1, |legs| legs.len()))
+        .sum();
+
+    let route_shapes = in_span(
+        "store_route_shapes",
+        vec![],
+        route_shapes::store_route_shapes(&db, delivery),
+    )
+    .await?;
+    let route_shapes = Arc::new(route_shapes);
+
+    // bounded, so services are only split into legs about as fast as they can be written
+    let (job_tx, job_rx) = async_channel::bounded::<JourneyProcessingJob>(options.queue_capacity);
+    let (result_tx, result_rx) = async_channel::unbounded::<(u32, Result<ProcessingResult>)>();
+
+    let worker_handles = (0..options.workers)
+        .map(|id| tokio::spawn(worker(id, job_rx.clone(), result_tx.clone(), options)))
         .collect::<Vec<_>>();
-    drop(result_tx);
 
-    let collector_handle = tokio::spawn(collect_results(result_rx));
+    let collector_handle = tokio::spawn(collect_results(result_rx, total));
 
-    let services = timetable.data.iter().flat_map(Service::split_legs);
-    for service in services {
-        let job = JourneyProcessingJob {
-            db: Arc::clone(&db),
-            service,
-            timetable: Arc::clone(&timetable),
-            footnotes: Arc::clone(&footnotes),
-            companies: Arc::clone(&companies),
+    'services: for service in &delivery.timetable.data {
+        let legs = match service.split_legs() {
+            Ok(legs) => legs,
+            Err(e) => {
+                let error = anyhow!("! failed to split service into legs: {e:#}");
+                let _ = result_tx.send((service.identification.0, Err(error))).await;
+                continue;
+            }
         };
 
-        if job_tx.send(job).await.is_err() {
-            eprintln!("! job receiver has been dropped, aborting");
-            break;
+        for leg in legs {
+            let job = JourneyProcessingJob {
+                db: Arc::clone(&db),
+                service: leg,
+                timetable: Arc::clone(&delivery.timetable),
+                footnotes: Arc::clone(&delivery.footnotes),
+                companies: Arc::clone(&delivery.companies),
+                window,
+                route_shapes: Arc::clone(&route_shapes),
+                trace_context: TraceContext::current(),
+            };
+
+            if job_tx.send(job).await.is_err() {
+                error!(logger(), "Job receiver has been dropped, aborting");
+                break 'services;
+            }
         }
     }
 
+    drop(result_tx);
+
     drop(job_tx);
 
     for handle in worker_handles {
         handle.await?;
     }
-    collector_handle.await?;
 
-    println!("+ All done!");
+    Ok(collector_handle.await?)
+}
+
+/// Deliveries parsed from inline files, shared by the tests of the modules that need one.
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+
+    /// Starts every file of a test delivery, which is valid from 1 up to and including 5 June 2025.
+    pub(crate) const IDENTIFICATION: &str = "@100,01062025,05062025,0001,IFF Standaard uit RIF\r\n";
+
+    /// A delivery of which every file consists of `identification` followed by the given records.
+    pub(crate) fn test_delivery(
+        identification: &str,
+        timetable: &str,
+        footnotes: &str,
+        companies: &str,
+        stations: &str,
+        changes: &str,
+    ) -> Delivery {
+        let file = |records: &str| format!("{identification}{records}");
+
+        Delivery {
+            timetable: Arc::new(timetable_file(&file(timetable)).unwrap().1),
+            footnotes: Arc::new(footnote_file(&file(footnotes)).unwrap().1),
+            companies: Arc::new(company_file(&file(companies)).unwrap().1),
+            stations: Arc::new(station_file(&file(stations)).unwrap().1),
+            changes: Arc::new(change_file(&file(changes)).unwrap().1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::test_support::IDENTIFICATION;
+    use super::*;
+    use std::{env, fs, io::Write};
+    use zip::ZipWriter;
+    use zip::write::SimpleFileOptions;
+
+    #[test]
+    fn it_loads_delivery_from_zip() {
+        let path = env::temp_dir().join(format!("kedeng-delivery-{}.zip", Uuid::new_v4()));
+
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        let files = [
+            (
+                "timetbls.dat",
+                format!(
+                    "{IDENTIFICATION}#00000001\r
+%100,01234,      ,001,002,                              \r
+-00001,000,999\r
+&SPR ,001,002\r
+>rtd    ,1000\r
+<gd     ,1020\r
+"
+                ),
+            ),
+            (
+                "footnote.dat",
+                format!("{IDENTIFICATION}#00001\r\n11001\r\n"),
+            ),
+            (
+                "company.dat",
+                format!("{IDENTIFICATION}100,NS        ,NS                            ,0000\r\n"),
+            ),
+            (
+                "stations.dat",
+                format!(
+                    "{IDENTIFICATION}1,rtd    ,03,03,NL  ,0000,  ,000000,000000,Rotterdam Centraal\r
+1,gd     ,02,02,NL  ,0000,  ,000000,000000,Gouda\r
+"
+                ),
+            ),
+            (
+                "changes.dat",
+                format!("{IDENTIFICATION}#gd\r\n-00000001,00000002,1\r\n"),
+            ),
+        ];
+        for (name, contents) in files {
+            // deliveries are sometimes zipped together with the directory they are in
+            zip.start_file(format!("ns-latest/{name}"), SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let delivery = load_delivery(&path).unwrap();
+
+        assert_eq!(delivery.timetable.data.len(), 1);
+        assert_eq!(delivery.footnotes.data.len(), 1);
+        assert_eq!(delivery.companies.data.len(), 1);
+        assert_eq!(delivery.stations.data.len(), 2);
+        assert_eq!(delivery.changes.data.len(), 1);
+        assert_eq!(delivery.running_dates(1).unwrap().len(), 3);
+
+        fs::remove_file(path).unwrap();
+    }
 
-    Ok(())
+    #[test]
+    fn it_loads_delivery_without_changes() {
+        let dir = env::temp_dir().join(format!("kedeng-delivery-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        for name in [
+            "timetbls.dat",
+            "footnote.dat",
+            "company.dat",
+            "stations.dat",
+        ] {
+            fs::write(dir.join(name), IDENTIFICATION).unwrap();
+        }
+
+        let delivery = load_delivery(&dir).unwrap();
+
+        assert!(delivery.changes.data.is_empty());
+        assert_eq!(
+            delivery.changes.identification,
+            delivery.timetable.identification
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_reads_service_ids() {
+        let path = env::temp_dir().join(format!("kedeng-services-{}.txt", Uuid::new_v4()));
+
+        fs::write(&path, "1234\n\n 5678 \n").unwrap();
+        assert_eq!(
+            read_service_ids(&path).unwrap(),
+            HashSet::from([1234, 5678])
+        );
+
+        fs::write(&path, "1234\nrtd\n").unwrap();
+        assert!(read_service_ids(&path).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,321 @@
+use crate::importers::timetable::parsers::identification::identification;
+use crate::logging::logger;
+use anyhow::{Context, Result, anyhow, bail};
+use reqwest::StatusCode;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+use slog::info;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+use zip::ZipArchive;
+
+const ARCHIVE_FILE: &str = "ns-latest.zip";
+const METADATA_FILE: &str = "ns-latest.json";
+const DELIVERIES_DIR: &str = "deliveries";
+const TEMP_PREFIX: &str = ".tmp-";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Maximum time without receiving any data. The archive is too large for a timeout on the whole
+/// download, but a stalled one should not hold up `watch` forever.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Validators of the cached archive, sent along with the next download to only get a new
+/// archive if it actually changed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheMetadata {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Persistent cache for timetable deliveries. The last downloaded archive is kept together with
+/// its `ETag` and `Last-Modified`, and the last `keep` deliveries are kept extracted in a
+/// directory per version number.
+#[derive(Debug, Clone)]
+pub struct DeliveryCache {
+    pub dir: PathBuf,
+    pub keep: usize,
+}
+
+/// Reads every entry of the archive to the end, which makes the zip reader check its CRC, and
+/// returns the version number of the delivery in it.
+fn verify_archive<R: Read + Seek>(reader: R) -> Result<String> {
+    let mut archive = ZipArchive::new(reader).context("! archive is not a valid zip")?;
+
+    let mut delivery = None;
+    for idx in 0..archive.len() {
+        let mut entry = archive.by_index(idx)?;
+        let name = entry.name().to_string();
+
+        if name.rsplit('/').next() == Some("delivery.dat") {
+            let mut contents = Vec::new();
+            entry
+                .read_to_end(&mut contents)
+                .context(format!("! {name} is corrupt"))?;
+            delivery = Some(contents);
+        } else {
+            io::copy(&mut entry, &mut io::sink()).context(format!("! {name} is corrupt"))?;
+        }
+    }
+
+    let delivery = delivery.context("! archive does not contain delivery.dat")?;
+    // the identification line is plain ASCII, so the ISO-8859-1 encoding does not matter here
+    let delivery = String::from_utf8_lossy(&delivery);
+    let (_, identification) =
+        identification(&delivery).map_err(|_| anyhow!("! failed to parse delivery.dat"))?;
+
+    Ok(identification.version_number)
+}
+
+impl DeliveryCache {
+    fn deliveries_dir(&self) -> PathBuf {
+        self.dir.join(DELIVERIES_DIR)
+    }
+
+    fn read_metadata(&self) -> CacheMetadata {
+        fs::read_to_string(self.dir.join(METADATA_FILE))
+            .ok()
+            .and_then(|metadata| serde_json::from_str(&metadata).ok())
+            .unwrap_or_default()
+    }
+
+    /// Downloads the archive at `url` unless the cached one is still current.
+    async fn download(&self, url: &str) -> Result<()> {
+        let archive_path = self.dir.join(ARCHIVE_FILE);
+
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .read_timeout(READ_TIMEOUT)
+            .build()?;
+        let mut request = client.get(url);
+        if archive_path.exists() {
+            let metadata = self.read_metadata();
+            if let Some(etag) = metadata.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = metadata.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            info!(logger(), "Cached archive is up to date");
+            return Ok(());
+        }
+
+        let response = response.error_for_status()?;
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let metadata = CacheMetadata {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+
+        let archive = response.bytes().await?;
+        verify_archive(io::Cursor::new(&archive))?;
+
+        // only replace the cached archive once the new one is known to be complete
+        let temp_path = self
+            .dir
+            .join(format!("{TEMP_PREFIX}{}.zip", Uuid::new_v4()));
+        fs::write(&temp_path, &archive).context("! failed to write archive")?;
+        fs::rename(&temp_path, &archive_path).context("! failed to move archive into cache")?;
+        fs::write(
+            self.dir.join(METADATA_FILE),
+            serde_json::to_string(&metadata)?,
+        )?;
+
+        info!(logger(), "Downloaded new archive"; "bytes" => archive.len());
+
+        Ok(())
+    }
+
+    fn extract(&self, archive_path: &Path, version_number: &str) -> Result<PathBuf> {
+        let delivery_dir = self.deliveries_dir().join(version_number);
+        if delivery_dir.exists() {
+            info!(logger(), "Delivery is already extracted"; "version" => version_number);
+            return Ok(delivery_dir);
+        }
+
+        let temp_dir = self
+            .deliveries_dir()
+            .join(format!("{TEMP_PREFIX}{}", Uuid::new_v4()));
+        fs::create_dir_all(&temp_dir)?;
+
+        zip_extract::extract(File::open(archive_path)?, &temp_dir, true)
+            .context("! failed to extract zip")?;
+        fs::rename(&temp_dir, &delivery_dir).context("! failed to move delivery into cache")?;
+
+        info!(logger(), "Extracted delivery"; "version" => version_number);
+
+        Ok(delivery_dir)
+    }
+
+    /// Removes all but the newest `keep` deliveries, never removing `current`, along with
+    /// anything left behind by runs that were interrupted.
+    fn clean_up(&self, current: &str) -> Result<()> {
+        let mut versions = Vec::new();
+        for entry in fs::read_dir(self.deliveries_dir())? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+
+            if name.starts_with(TEMP_PREFIX) {
+                fs::remove_dir_all(&path)?;
+            } else if name != current {
+                versions.push(name);
+            }
+        }
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.is_file()
+                && path
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with(TEMP_PREFIX))
+            {
+                fs::remove_file(path)?;
+            }
+        }
+
+        // version numbers are zero-padded, but compare them as numbers in case that ever changes
+        versions.sort_by_key(|version| std::cmp::Reverse(version.parse::<u64>().unwrap_or(0)));
+        for version in versions.iter().skip(self.keep.saturating_sub(1)) {
+            info!(logger(), "Removing old delivery"; "version" => version);
+            fs::remove_dir_all(self.deliveries_dir().join(version))?;
+        }
+
+        Ok(())
+    }
+
+    /// Makes sure the latest delivery at `url` is in the cache and returns the directory it was
+    /// extracted to.
+    pub async fn fetch(&self, url: &str) -> Result<PathBuf> {
+        fs::create_dir_all(self.deliveries_dir()).context("! failed to create cache dir")?;
+        info!(logger(), "Using cache dir"; "path" => self.dir.display().to_string());
+
+        self.download(url).await?;
+
+        let archive_path = self.dir.join(ARCHIVE_FILE);
+        let version_number = verify_archive(File::open(&archive_path)?)?;
+        if version_number.is_empty() {
+            bail!("! delivery.dat has no version number");
+        }
+
+        let delivery_dir = self.extract(&archive_path, &version_number)?;
+        self.clean_up(&version_number)?;
+
+        Ok(delivery_dir)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+    use wiremock::matchers::{header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use zip::ZipWriter;
+    use zip::write::SimpleFileOptions;
+
+    fn delivery_archive(version_number: &str) -> Vec<u8> {
+        let mut zip = ZipWriter::new(io::Cursor::new(Vec::new()));
+        zip.start_file("delivery.dat", SimpleFileOptions::default())
+            .unwrap();
+        write!(
+            zip,
+            "@100,07042025,13122025,{version_number},IFF Standaard uit RIF\r\n"
+        )
+        .unwrap();
+        zip.start_file("timetbls.dat", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"").unwrap();
+
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn test_cache(keep: usize) -> DeliveryCache {
+        DeliveryCache {
+            dir: env::temp_dir().join(format!("kedeng-cache-{}", Uuid::new_v4())),
+            keep,
+        }
+    }
+
+    #[test]
+    fn it_rejects_corrupt_archives() {
+        let mut archive = delivery_archive("0070");
+        assert_eq!(verify_archive(io::Cursor::new(&archive)).unwrap(), "0070");
+
+        // flip a byte in the compressed data of the first entry
+        archive[45] ^= 0xff;
+        assert!(verify_archive(io::Cursor::new(&archive)).is_err());
+        assert!(verify_archive(io::Cursor::new(b"not a zip")).is_err());
+    }
+
+    #[tokio::test]
+    async fn it_only_downloads_changed_archives() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("If-None-Match", "\"v70\""))
+            .respond_with(ResponseTemplate::new(304))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("ETag", "\"v70\"")
+                    .set_body_bytes(delivery_archive("0070")),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let cache = test_cache(2);
+
+        let delivery_dir = cache.fetch(&server.uri()).await.unwrap();
+        assert_eq!(delivery_dir, cache.dir.join("deliveries/0070"));
+        assert!(delivery_dir.join("delivery.dat").exists());
+
+        assert_eq!(cache.fetch(&server.uri()).await.unwrap(), delivery_dir);
+
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_last_deliveries() {
+        let cache = test_cache(2);
+
+        for version_number in ["0068", "0069", "0070"] {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .respond_with(
+                    ResponseTemplate::new(200).set_body_bytes(delivery_archive(version_number)),
+                )
+                .mount(&server)
+                .await;
+
+            cache.fetch(&server.uri()).await.unwrap();
+        }
+
+        let mut deliveries = fs::read_dir(cache.deliveries_dir())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        deliveries.sort();
+
+        assert_eq!(deliveries, vec!["0069", "0070"]);
+
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
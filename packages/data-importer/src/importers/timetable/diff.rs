@@ -0,0 +1,571 @@
+use crate::importers::timetable::parsers::service::ServiceLeg;
+use crate::importers::timetable::quality::ReportFormat;
+use crate::importers::timetable::{Delivery, load_delivery};
+use anyhow::Result;
+use chrono::{NaiveDate, NaiveTime};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct StopTimes {
+    pub arrival: Option<NaiveTime>,
+    pub departure: Option<NaiveTime>,
+}
+
+impl Display for StopTimes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let format = |time: Option<NaiveTime>| {
+            time.map_or("--:--".to_string(), |time| time.format("%H:%M").to_string())
+        };
+        write!(f, "{}/{}", format(self.arrival), format(self.departure))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct StopPlatforms {
+    pub arrival: Option<String>,
+    pub departure: Option<String>,
+}
+
+impl Display for StopPlatforms {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{}",
+            self.arrival.as_deref().unwrap_or("-"),
+            self.departure.as_deref().unwrap_or("-")
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum ServiceChange {
+    RunningDays {
+        added: BTreeSet<NaiveDate>,
+        removed: BTreeSet<NaiveDate>,
+    },
+    StopPattern {
+        added: BTreeSet<String>,
+        removed: BTreeSet<String>,
+    },
+    /// Legs with a stop pattern both deliveries have, but that only one of them runs this often
+    Legs {
+        pattern: String,
+        added: usize,
+        removed: usize,
+    },
+    /// Stops past the end of the other delivery's leg it is compared with
+    Stops {
+        added: Vec<String>,
+        removed: Vec<String>,
+    },
+    Times {
+        station: String,
+        old: StopTimes,
+        new: StopTimes,
+    },
+    Platform {
+        station: String,
+        old: StopPlatforms,
+        new: StopPlatforms,
+    },
+}
+
+impl Display for ServiceChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let join = |items: Vec<String>| items.join(", ");
+
+        match self {
+            ServiceChange::RunningDays { added, removed } => write!(
+                f,
+                "running days: +[{}] -[{}]",
+                join(added.iter().map(NaiveDate::to_string).collect()),
+                join(removed.iter().map(NaiveDate::to_string).collect())
+            ),
+            ServiceChange::StopPattern { added, removed } => write!(
+                f,
+                "stops: +[{}] -[{}]",
+                join(added.iter().cloned().collect()),
+                join(removed.iter().cloned().collect())
+            ),
+            ServiceChange::Legs {
+                pattern,
+                added,
+                removed,
+            } => write!(f, "legs along {pattern}: +{added} -{removed}"),
+            ServiceChange::Stops { added, removed } => write!(
+                f,
+                "stops at the end: +[{}] -[{}]",
+                join(added.clone()),
+                join(removed.clone())
+            ),
+            ServiceChange::Times { station, old, new } => {
+                write!(f, "times at {station}: {old} -> {new}")
+            }
+            ServiceChange::Platform { station, old, new } => {
+                write!(f, "platform at {station}: {old} -> {new}")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DeliveryDiff {
+    pub old_version: String,
+    pub new_version: String,
+    pub added: BTreeSet<String>,
+    pub removed: BTreeSet<String>,
+    pub changed: BTreeMap<String, BTreeSet<ServiceChange>>,
+    /// Train numbers of services that could not be split into legs in either delivery. These are
+    /// left out of the comparison, so they don't show up as added or removed.
+    pub unparseable: BTreeSet<String>,
+}
+
+impl DeliveryDiff {
+    pub fn to_text(&self) -> String {
+        let mut text = format!("delivery {} -> {}\n", self.old_version, self.new_version);
+        for train_number in &self.added {
+            text.push_str(&format!("+ {train_number}\n"));
+        }
+        for train_number in &self.removed {
+            text.push_str(&format!("- {train_number}\n"));
+        }
+        for (train_number, changes) in &self.changed {
+            text.push_str(&format!("~ {train_number}\n"));
+            for change in changes {
+                text.push_str(&format!("    {change}\n"));
+            }
+        }
+        for train_number in &self.unparseable {
+            text.push_str(&format!("? {train_number}\n"));
+        }
+
+        text
+    }
+}
+
+struct StopSummary {
+    station: String,
+    times: StopTimes,
+    platforms: StopPlatforms,
+}
+
+struct LegSummary {
+    stops: Vec<StopSummary>,
+    running_dates: BTreeSet<NaiveDate>,
+}
+
+impl LegSummary {
+    fn new(leg: &ServiceLeg, delivery: &Delivery) -> Self {
+        let stops = leg
+            .stops()
+            .map(|(event, platform_info)| StopSummary {
+                station: event.station.clone(),
+                times: StopTimes {
+                    arrival: event.arrival_time,
+                    departure: event.departure_time,
+                },
+                platforms: StopPlatforms {
+                    arrival: platform_info.as_ref().map(|p| p.arrival_platform.clone()),
+                    departure: platform_info.as_ref().map(|p| p.departure_platform.clone()),
+                },
+            })
+            .collect();
+
+        let running_dates = delivery
+            .running_dates(leg.validity.footnote)
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+
+        LegSummary {
+            stops,
+            running_dates,
+        }
+    }
+
+    fn pattern(&self) -> String {
+        self.stops
+            .iter()
+            .map(|stop| stop.station.as_str())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn first_departure(&self) -> Option<NaiveTime> {
+        self.stops.first().and_then(|stop| stop.times.departure)
+    }
+}
+
+/// Summaries of all legs per train number, and the train numbers of services that could not be
+/// split into legs.
+fn summarize(delivery: &Delivery) -> (BTreeMap<String, Vec<LegSummary>>, BTreeSet<String>) {
+    let mut summaries: BTreeMap<String, Vec<LegSummary>> = BTreeMap::new();
+    let mut unparseable = BTreeSet::new();
+    for service in &delivery.timetable.data {
+        let Ok(legs) = service.split_legs() else {
+            unparseable.extend(
+                service
+                    .service_number
+                    .iter()
+                    .filter_map(|n| n.train_number()),
+            );
+            continue;
+        };
+
+        for leg in legs {
+            if let Some(train_number) = leg.train_number() {
+                summaries
+                    .entry(train_number)
+                    .or_default()
+                    .push(LegSummary::new(&leg, delivery));
+            }
+        }
+    }
+
+    (summaries, unparseable)
+}
+
+fn group_by_pattern(legs: &[LegSummary]) -> BTreeMap<String, Vec<&LegSummary>> {
+    let mut patterns: BTreeMap<String, Vec<&LegSummary>> = BTreeMap::new();
+    for leg in legs {
+        patterns.entry(leg.pattern()).or_default().push(leg);
+    }
+    for legs in patterns.values_mut() {
+        legs.sort_by_key(|leg| leg.first_departure());
+    }
+
+    patterns
+}
+
+fn diff_legs(
+    old: &[LegSummary],
+    new: &[LegSummary],
+    overlap: (NaiveDate, NaiveDate),
+) -> BTreeSet<ServiceChange> {
+    let mut changes = BTreeSet::new();
+
+    let running_dates = |legs: &[LegSummary]| {
+        legs.iter()
+            .flat_map(|leg| leg.running_dates.iter().copied())
+            .filter(|date| overlap.0 <= *date && *date <= overlap.1)
+            .collect::<BTreeSet<_>>()
+    };
+    let (old_dates, new_dates) = (running_dates(old), running_dates(new));
+    if old_dates != new_dates {
+        changes.insert(ServiceChange::RunningDays {
+            added: new_dates.difference(&old_dates).copied().collect(),
+            removed: old_dates.difference(&new_dates).copied().collect(),
+        });
+    }
+
+    let (old_patterns, new_patterns) = (group_by_pattern(old), group_by_pattern(new));
+
+    let old_keys = old_patterns.keys().cloned().collect::<BTreeSet<_>>();
+    let new_keys = new_patterns.keys().cloned().collect::<BTreeSet<_>>();
+    if old_keys != new_keys {
+        changes.insert(ServiceChange::StopPattern {
+            added: new_keys.difference(&old_keys).cloned().collect(),
+            removed: old_keys.difference(&new_keys).cloned().collect(),
+        });
+    }
+
+    // legs with the same stop pattern are compared stop by stop, in order of departure
+    for (pattern, old_legs) in &old_patterns {
+        let Some(new_legs) = new_patterns.get(pattern) else {
+            continue;
+        };
+
+        if old_legs.len() != new_legs.len() {
+            changes.insert(ServiceChange::Legs {
+                pattern: pattern.clone(),
+                added: new_legs.len().saturating_sub(old_legs.len()),
+                removed: old_legs.len().saturating_sub(new_legs.len()),
+            });
+        }
+
+        for (old_leg, new_leg) in old_legs.iter().zip(new_legs) {
+            let stations = |stops: &[StopSummary], from: usize| {
+                stops
+                    .iter()
+                    .skip(from)
+                    .map(|stop| stop.station.clone())
+                    .collect::<Vec<_>>()
+            };
+            if old_leg.stops.len() != new_leg.stops.len() {
+                changes.insert(ServiceChange::Stops {
+                    added: stations(&new_leg.stops, old_leg.stops.len()),
+                    removed: stations(&old_leg.stops, new_leg.stops.len()),
+                });
+            }
+
+            for (old_stop, new_stop) in old_leg.stops.iter().zip(&new_leg.stops) {
+                if old_stop.times != new_stop.times {
+                    changes.insert(ServiceChange::Times {
+                        station: old_stop.station.clone(),
+                        old: old_stop.times.clone(),
+                        new: new_stop.times.clone(),
+                    });
+                }
+
+                if old_stop.platforms != new_stop.platforms {
+                    changes.insert(ServiceChange::Platform {
+                        station: old_stop.station.clone(),
+                        old: old_stop.platforms.clone(),
+                        new: new_stop.platforms.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    changes
+}
+
+/// Compares two deliveries per train number. Running days are only compared for the dates both
+/// deliveries are valid on.
+pub fn diff_deliveries(old: &Delivery, new: &Delivery) -> DeliveryDiff {
+    let old_identification = &old.timetable.identification;
+    let new_identification = &new.timetable.identification;
+    let overlap = (
+        old_identification
+            .first_valid
+            .max(new_identification.first_valid),
+        old_identification
+            .last_valid
+            .min(new_identification.last_valid),
+    );
+
+    let (old_services, old_unparseable) = summarize(old);
+    let (new_services, new_unparseable) = summarize(new);
+
+    let mut diff = DeliveryDiff {
+        old_version: old_identification.version_number.clone(),
+        new_version: new_identification.version_number.clone(),
+        unparseable: old_unparseable.union(&new_unparseable).cloned().collect(),
+        ..Default::default()
+    };
+
+    for (train_number, old_legs) in &old_services {
+        if diff.unparseable.contains(train_number) {
+            continue;
+        }
+
+        let Some(new_legs) = new_services.get(train_number) else {
+            diff.removed.insert(train_number.clone());
+            continue;
+        };
+
+        let changes = diff_legs(old_legs, new_legs, overlap);
+        if !changes.is_empty() {
+            diff.changed.insert(train_number.clone(), changes);
+        }
+    }
+
+    diff.added = new_services
+        .keys()
+        .filter(|train_number| {
+            !old_services.contains_key(*train_number) && !diff.unparseable.contains(*train_number)
+        })
+        .cloned()
+        .collect();
+
+    diff
+}
+
+pub fn diff(old_path: String, new_path: String, format: ReportFormat) -> Result<()> {
+    let old = load_delivery(&PathBuf::from(old_path))?;
+    let new = load_delivery(&PathBuf::from(new_path))?;
+
+    let diff = diff_deliveries(&old, &new);
+    match format {
+        ReportFormat::Text => print!("{}", diff.to_text()),
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&diff)?),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::importers::timetable::test_support::{IDENTIFICATION, test_delivery};
+
+    fn delivery(identification: &str, timetable: &str, footnotes: &str) -> Delivery {
+        test_delivery(identification, timetable, footnotes, "", "", "")
+    }
+
+    #[test]
+    fn it_diffs_deliveries() {
+        let old = delivery(
+            IDENTIFICATION,
+            "#00000001\r
+%100,01234,      ,001,003,                              \r
+-00001,000,999\r
+&SPR ,001,003\r
+>rtd    ,1000\r
+?1    ,1    ,00001\r
+.gd     ,1020\r
+?2    ,2    ,00001\r
+<ut     ,1050\r
+?3    ,3    ,00001\r
+#00000002\r
+%100,05678,      ,001,002,                              \r
+-00000,000,999\r
+&IC  ,001,002\r
+>rtd    ,1100\r
+?1    ,1    ,00001\r
+<asd    ,1200\r
+?3    ,3    ,00001\r
+",
+            "#00001\r\n11111\r\n",
+        );
+
+        let new = delivery(
+            "@100,02062025,06062025,0002,IFF Standaard uit RIF\r\n",
+            "#00000001\r
+%100,01234,      ,001,003,                              \r
+-00001,000,999\r
+&SPR ,001,003\r
+>rtd    ,1000\r
+?1    ,1    ,00001\r
+.gd     ,1022\r
+?4    ,4    ,00001\r
+<ut     ,1050\r
+?3    ,3    ,00001\r
+#00000002\r
+%100,09999,      ,001,002,                              \r
+-00000,000,999\r
+&IC  ,001,002\r
+>rtd    ,1100\r
+?1    ,1    ,00001\r
+<asd    ,1200\r
+?3    ,3    ,00001\r
+",
+            "#00001\r\n10111\r\n",
+        );
+
+        let diff = diff_deliveries(&old, &new);
+
+        assert_eq!(diff.added, BTreeSet::from(["9999".to_string()]));
+        assert_eq!(diff.removed, BTreeSet::from(["5678".to_string()]));
+
+        let time = |h, m| NaiveTime::from_hms_opt(h, m, 0);
+        let changes = diff.changed.get("1234").expect("1234 should be changed");
+        assert_eq!(
+            changes,
+            &BTreeSet::from([
+                ServiceChange::RunningDays {
+                    added: BTreeSet::new(),
+                    removed: BTreeSet::from([NaiveDate::from_ymd_opt(2025, 6, 3).unwrap()]),
+                },
+                ServiceChange::Times {
+                    station: "gd".to_string(),
+                    old: StopTimes {
+                        arrival: time(10, 20),
+                        departure: time(10, 20),
+                    },
+                    new: StopTimes {
+                        arrival: time(10, 22),
+                        departure: time(10, 22),
+                    },
+                },
+                ServiceChange::Platform {
+                    station: "gd".to_string(),
+                    old: StopPlatforms {
+                        arrival: Some("2".to_string()),
+                        departure: Some("2".to_string()),
+                    },
+                    new: StopPlatforms {
+                        arrival: Some("4".to_string()),
+                        departure: Some("4".to_string()),
+                    },
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn it_reports_legs_only_one_delivery_has() {
+        const TIMETABLE: &str = "#00000001\r
+%100,01234,      ,001,002,                              \r
+-00001,000,999\r
+&SPR ,001,002\r
+>rtd    ,1000\r
+<ut     ,1050\r
+";
+        let old = delivery(
+            IDENTIFICATION,
+            &format!(
+                "{TIMETABLE}#00000002\r
+%100,01234,      ,001,002,                              \r
+-00002,000,999\r
+&SPR ,001,002\r
+>rtd    ,1100\r
+<ut     ,1150\r
+"
+            ),
+            "#00001\r\n11000\r\n#00002\r\n00111\r\n",
+        );
+        let new = delivery(
+            IDENTIFICATION,
+            TIMETABLE,
+            "#00001\r\n11000\r\n#00002\r\n00111\r\n",
+        );
+
+        let diff = diff_deliveries(&old, &new);
+
+        let changes = diff.changed.get("1234").expect("1234 should be changed");
+        assert!(changes.contains(&ServiceChange::Legs {
+            pattern: "rtd ut".to_string(),
+            added: 0,
+            removed: 1,
+        }));
+    }
+
+    #[test]
+    fn it_reports_unparseable_services_separately() {
+        let old = delivery(
+            IDENTIFICATION,
+            "#00000001\r
+%100,01234,      ,001,002,                              \r
+-00000,000,999\r
+&SPR ,001,002\r
+>rtd    ,1000\r
+<ut     ,1050\r
+",
+            "#00000\r\n11111\r\n",
+        );
+
+        // three service numbers on one service cannot be split into legs
+        let new = delivery(
+            IDENTIFICATION,
+            "#00000001\r
+%100,01234,      ,001,002,                              \r
+%100,01235,      ,002,003,                              \r
+%100,01236,      ,003,004,                              \r
+-00000,000,999\r
+&SPR ,001,004\r
+>rtd    ,1000\r
+.gd     ,1020\r
+.wd     ,1035\r
+<ut     ,1050\r
+",
+            "#00000\r\n11111\r\n",
+        );
+
+        let diff = diff_deliveries(&old, &new);
+
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+        assert!(diff.changed.is_empty());
+        assert_eq!(
+            diff.unparseable,
+            BTreeSet::from(["1234".to_string(), "1235".to_string(), "1236".to_string()])
+        );
+        assert!(diff.to_text().contains("? 1234\n"));
+    }
+}
@@ -0,0 +1,116 @@
+/// Mean radius of the earth in metres, as used for the haversine formula.
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+/// Distance in metres between two (lng, lat) coordinates.
+fn haversine(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lng1, lat1) = (from.0.to_radians(), from.1.to_radians());
+    let (lng2, lat2) = (to.0.to_radians(), to.1.to_radians());
+
+    let a = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lng2 - lng1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+/// Length in metres of a line of (lng, lat) coordinates.
+pub(crate) fn line_length(line: &[(f64, f64)]) -> f64 {
+    line.windows(2)
+        .map(|pair| haversine(pair[0], pair[1]))
+        .sum()
+}
+
+/// Planned distance and speed for the segment that ends at an event.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct SegmentStats {
+    /// Metres along the track from the previous event
+    pub distance: Option<f64>,
+    /// Average km/h since departing from the previous stop, only for events with an arrival time
+    pub speed: Option<f64>,
+}
+
+/// Combines the track distance between consecutive events (`None` where there is no geometry) with
+/// the planned arrival and departure of every event, in minutes since midnight of the running date
+/// as returned by `ServiceLeg::event_minutes`. Passages have no times, so the distance since the
+/// last departure adds up until the next arrival. Speeds are left out where part of that distance
+/// is unknown or the train is planned to arrive in the same minute it departed.
+pub(crate) fn segment_stats(
+    times: &[(Option<u32>, Option<u32>)],
+    distances: &[Option<f64>],
+) -> Vec<SegmentStats> {
+    let mut stats = Vec::with_capacity(times.len());
+    let mut departure: Option<(u32, Option<f64>)> = None;
+
+    for ((arrival, event_departure), distance) in times.iter().zip(distances) {
+        let travelled = departure.and_then(|(_, travelled)| Some(travelled? + (*distance)?));
+        let speed = departure.zip(*arrival).zip(travelled).and_then(
+            |(((departed, _), arrived), travelled)| {
+                let seconds = (arrived.saturating_sub(departed) * 60) as f64;
+                (seconds > 0.0).then(|| travelled / seconds * 3.6)
+            },
+        );
+
+        stats.push(SegmentStats {
+            distance: *distance,
+            speed,
+        });
+
+        departure = match event_departure {
+            Some(departed) => Some((*departed, Some(0.0))),
+            None => departure.map(|(departed, _)| (departed, travelled)),
+        };
+    }
+
+    stats
+}
+
+/// Total metres of a journey, unknown if the distance between any two events is.
+pub(crate) fn total_distance(distances: &[Option<f64>]) -> Option<f64> {
+    distances.iter().skip(1).copied().sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn minutes(day: u32, hours: u32, minutes: u32) -> Option<u32> {
+        Some(day * 24 * 60 + hours * 60 + minutes)
+    }
+
+    #[test]
+    fn it_measures_lines() {
+        // a degree of latitude is about 111 km
+        let length = line_length(&[(5.0, 52.0), (5.0, 52.5), (5.0, 53.0)]);
+        assert!((length - 111_195.0).abs() < 10.0);
+    }
+
+    #[test]
+    fn it_computes_speeds_across_passages() {
+        let times = [
+            (None, minutes(0, 23, 50)),
+            (None, None),
+            (minutes(1, 0, 10), minutes(1, 0, 12)),
+            (minutes(1, 0, 30), None),
+        ];
+        let distances = [None, Some(10_000.0), Some(20_000.0), None];
+
+        let stats = segment_stats(&times, &distances);
+
+        // 30 km in 20 minutes, past midnight
+        assert_eq!(stats[1].speed, None);
+        assert_eq!(stats[2].distance, Some(20_000.0));
+        assert!((stats[2].speed.unwrap() - 90.0).abs() < 1e-9);
+        // no geometry between gd and ut
+        assert_eq!(stats[3], SegmentStats::default());
+
+        assert_eq!(total_distance(&distances), None);
+        assert_eq!(total_distance(&distances[..3]), Some(30_000.0));
+    }
+
+    #[test]
+    fn it_computes_speeds_of_legs_longer_than_a_day() {
+        let times = [(None, minutes(0, 10, 0)), (minutes(1, 10, 0), None)];
+
+        let stats = segment_stats(&times, &[None, Some(2_400_000.0)]);
+
+        assert!((stats[1].speed.unwrap() - 100.0).abs() < 1e-9);
+    }
+}
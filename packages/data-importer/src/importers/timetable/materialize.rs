@@ -0,0 +1,214 @@
+use crate::db;
+use crate::importers::timetable::{DateWindow, WorkerOptions, load_delivery, process_delivery};
+use crate::logging::logger;
+use anyhow::{Context, Result};
+use chrono::{Days, NaiveDate, Utc};
+use deadpool_postgres::Pool;
+use sea_query::{Cond, Expr, PostgresQueryBuilder, Query, SelectStatement};
+use sea_query_postgres::PostgresBinder;
+use slog::{error, info};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub struct MaterializeOptions {
+    pub input_path: String,
+    pub horizon_days: u64,
+    pub retention_days: u64,
+    pub interval: Option<Duration>,
+    pub workers: WorkerOptions,
+}
+
+fn materialization_window(today: NaiveDate, horizon_days: u64) -> DateWindow {
+    DateWindow {
+        first: today,
+        last: today
+            .checked_add_days(Days::new(horizon_days.saturating_sub(1)))
+            .unwrap_or(NaiveDate::MAX),
+    }
+}
+
+fn retention_cutoff(today: NaiveDate, retention_days: u64) -> NaiveDate {
+    today
+        .checked_sub_days(Days::new(retention_days))
+        .unwrap_or(NaiveDate::MIN)
+}
+
+/// Journeys running before `cutoff` that only have planned data. Once the persister has written
+/// realtime data or rolling stock for a journey, it is history rather than timetable, so it is
+/// kept.
+fn planned_journeys_before(cutoff: NaiveDate) -> SelectStatement {
+    let realtime_events = Query::select()
+        .expr(Expr::val(1))
+        .from(db::JourneyEvent::Table)
+        .and_where(
+            Expr::col((db::JourneyEvent::Table, db::JourneyEvent::JourneyId))
+                .equals((db::Journey::Table, db::Journey::Id)),
+        )
+        .cond_where(
+            Cond::any()
+                .add(Expr::col(db::JourneyEvent::ArrivalTimeActual).is_not_null())
+                .add(Expr::col(db::JourneyEvent::ArrivalPlatformActual).is_not_null())
+                .add(Expr::col(db::JourneyEvent::DepartureTimeActual).is_not_null())
+                .add(Expr::col(db::JourneyEvent::DeparturePlatformActual).is_not_null())
+                .add(Expr::col(db::JourneyEvent::EventTypeActual).is_not_null())
+                .add(Expr::col(db::JourneyEvent::ArrivalCancelled).eq(true))
+                .add(Expr::col(db::JourneyEvent::DepartureCancelled).eq(true)),
+        )
+        .to_owned();
+
+    let rolling_stock = Query::select()
+        .expr(Expr::val(1))
+        .from(db::RollingStock::Table)
+        .and_where(
+            Expr::col((db::RollingStock::Table, db::RollingStock::JourneyId))
+                .equals((db::Journey::Table, db::Journey::Id)),
+        )
+        .to_owned();
+
+    Query::select()
+        .column((db::Journey::Table, db::Journey::Id))
+        .from(db::Journey::Table)
+        .and_where(Expr::col(db::Journey::RunningOn).lt(cutoff))
+        .and_where(Expr::exists(realtime_events).not())
+        .and_where(Expr::exists(rolling_stock).not())
+        .to_owned()
+}
+
+/// Removes the planned journeys (and their events) running before the given date, see
+/// [planned_journeys_before].
+async fn prune(db: &Pool, cutoff: NaiveDate) -> Result<(u64, u64)> {
+    let mut db = db.get().await?;
+    let transaction = db
+        .transaction()
+        .await
+        .context("failed to start transaction")?;
+
+    let (journey_event_sql, journey_event_params) = Query::delete()
+        .from_table(db::JourneyEvent::Table)
+        .and_where(
+            Expr::col(db::JourneyEvent::JourneyId).in_subquery(planned_journeys_before(cutoff)),
+        )
+        .build_postgres(PostgresQueryBuilder);
+
+    let deleted_journey_events = transaction
+        .execute(
+            journey_event_sql.as_str(),
+            &journey_event_params.as_params(),
+        )
+        .await
+        .context("! failed to prune journey events")?;
+
+    let (journey_sql, journey_params) = Query::delete()
+        .from_table(db::Journey::Table)
+        .and_where(Expr::col(db::Journey::Id).in_subquery(planned_journeys_before(cutoff)))
+        .build_postgres(PostgresQueryBuilder);
+
+    let deleted_journeys = transaction
+        .execute(journey_sql.as_str(), &journey_params.as_params())
+        .await
+        .context("! failed to prune journeys")?;
+
+    transaction
+        .commit()
+        .await
+        .context("! could not commit transaction")?;
+
+    Ok((deleted_journeys, deleted_journey_events))
+}
+
+async fn run_once(db: Arc<Pool>, options: &MaterializeOptions) -> Result<()> {
+    let today = Utc::now().date_naive();
+    let window = materialization_window(today, options.horizon_days);
+    info!(logger(), "Materializing journeys";
+        "first" => window.first.to_string(),
+        "last" => window.last.to_string(),
+    );
+
+    let delivery = load_delivery(&PathBuf::from(&options.input_path))?;
+    process_delivery(Arc::clone(&db), &delivery, Some(window), options.workers).await?;
+
+    let cutoff = retention_cutoff(today, options.retention_days);
+    let (deleted_journeys, deleted_journey_events) = prune(&db, cutoff).await?;
+    info!(logger(), "Pruned old planned journeys";
+        "journeys" => deleted_journeys,
+        "journey_events" => deleted_journey_events,
+        "cutoff" => cutoff.to_string(),
+    );
+
+    Ok(())
+}
+
+/// Creates journeys for the next `horizon_days` days from the delivery at `input_path` and prunes
+/// the ones older than `retention_days`. With an interval set, this keeps on running and
+/// re-materializes the horizon every time the interval has passed.
+pub async fn materialize(db: Arc<Pool>, options: MaterializeOptions) -> Result<()> {
+    let Some(interval) = options.interval else {
+        run_once(db, &options).await?;
+        return Ok(());
+    };
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        if let Err(e) = run_once(Arc::clone(&db), &options).await {
+            error!(logger(), "Failed to materialize journeys"; "error" => format!("{e:#}"));
+        }
+
+        info!(logger(), "Waiting for next run"; "interval_secs" => interval.as_secs());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sea_query::Iden;
+
+    #[test]
+    fn it_computes_materialization_window() {
+        let today = NaiveDate::from_ymd_opt(2025, 6, 1).unwrap();
+        let window = materialization_window(today, 7);
+
+        assert_eq!(window.first, today);
+        assert_eq!(window.last, NaiveDate::from_ymd_opt(2025, 6, 7).unwrap());
+        assert!(window.contains(&NaiveDate::from_ymd_opt(2025, 6, 7).unwrap()));
+        assert!(!window.contains(&NaiveDate::from_ymd_opt(2025, 6, 8).unwrap()));
+        assert!(!window.contains(&NaiveDate::from_ymd_opt(2025, 5, 31).unwrap()));
+    }
+
+    #[test]
+    fn it_computes_retention_cutoff() {
+        let today = NaiveDate::from_ymd_opt(2025, 6, 1).unwrap();
+
+        assert_eq!(
+            retention_cutoff(today, 30),
+            NaiveDate::from_ymd_opt(2025, 5, 2).unwrap()
+        );
+    }
+
+    #[test]
+    fn it_keeps_journeys_with_realtime_data() {
+        let sql = planned_journeys_before(NaiveDate::from_ymd_opt(2025, 5, 2).unwrap())
+            .to_string(PostgresQueryBuilder);
+        let quoted = |iden: &dyn Iden| format!("\"{}\"", iden.to_string());
+
+        assert!(sql.contains("'2025-05-02'"));
+        // one guard for realtime events and one for rolling stock
+        assert_eq!(sql.matches("NOT EXISTS").count(), 2);
+        assert!(sql.contains(&quoted(&db::JourneyEvent::Table)));
+        assert!(sql.contains(&quoted(&db::RollingStock::Table)));
+        for column in [
+            db::JourneyEvent::ArrivalTimeActual,
+            db::JourneyEvent::ArrivalPlatformActual,
+            db::JourneyEvent::DepartureTimeActual,
+            db::JourneyEvent::DeparturePlatformActual,
+            db::JourneyEvent::EventTypeActual,
+            db::JourneyEvent::ArrivalCancelled,
+            db::JourneyEvent::DepartureCancelled,
+        ] {
+            let column = quoted(&column);
+            assert!(sql.contains(&column), "missing guard on {column}");
+        }
+    }
+}
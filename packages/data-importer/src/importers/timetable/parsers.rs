@@ -1,3 +1,4 @@
+pub mod change;
 pub mod chrono;
 pub mod company;
 pub mod footnote;
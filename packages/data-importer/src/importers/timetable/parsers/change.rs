@@ -0,0 +1,138 @@
+use nom::{
+    AsChar, IResult, Parser,
+    bytes::complete::{tag, take_till, take_while},
+    character::complete::{char, line_ending, one_of},
+    combinator::map_res,
+    multi::many0,
+    sequence::{delimited, terminated},
+};
+use std::str::FromStr;
+
+use super::{
+    identification::{DeliveryIdentified, identification},
+    utils::is_eol,
+};
+
+/// Whether passengers can change from one service to another, regardless of the minimum transfer
+/// time of the station.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ChangePossibility {
+    NotPossible,
+    Possible,
+    /// The connecting service waits for the arriving one
+    Guaranteed,
+}
+
+/// A transfer between two services at a station that overrides the station's default.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Change {
+    pub station: String,
+    pub from_service: u32,
+    pub to_service: u32,
+    pub possibility: ChangePossibility,
+}
+
+fn possibility(input: &str) -> IResult<&str, ChangePossibility> {
+    let (input, possibility) = one_of("012").parse(input)?;
+    let possibility = match possibility {
+        '0' => ChangePossibility::NotPossible,
+        '1' => ChangePossibility::Possible,
+        _ => ChangePossibility::Guaranteed,
+    };
+
+    Ok((input, possibility))
+}
+
+fn change(input: &str) -> IResult<&str, (u32, u32, ChangePossibility)> {
+    let (input, _) = tag("-")(input)?;
+    let (input, (from_service, to_service, possibility)) = (
+        map_res(
+            terminated(take_while(AsChar::is_dec_digit), char(',')),
+            u32::from_str,
+        ),
+        map_res(
+            terminated(take_while(AsChar::is_dec_digit), char(',')),
+            u32::from_str,
+        ),
+        terminated(possibility, line_ending),
+    )
+        .parse(input)?;
+
+    Ok((input, (from_service, to_service, possibility)))
+}
+
+/// All changes at one station, which are listed under a `#` record with its code.
+pub fn station_changes(input: &str) -> IResult<&str, Vec<Change>> {
+    let (input, station) = delimited(tag("#"), take_till(is_eol), line_ending).parse(input)?;
+    let station = station.trim();
+
+    let (input, changes) = many0(change).parse(input)?;
+
+    Ok((
+        input,
+        changes
+            .into_iter()
+            .map(|(from_service, to_service, possibility)| Change {
+                station: station.to_string(),
+                from_service,
+                to_service,
+                possibility,
+            })
+            .collect(),
+    ))
+}
+
+pub type Changes = DeliveryIdentified<Vec<Change>>;
+
+pub fn change_file(input: &str) -> IResult<&str, Changes> {
+    let (input, (identification, changes)) =
+        (identification, many0(station_changes)).parse(input)?;
+    Ok((
+        input,
+        Changes {
+            identification,
+            data: changes.into_iter().flatten().collect(),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::util::read_iso_8859_1_file;
+
+    use super::*;
+
+    #[test]
+    fn it_parses_station_changes() {
+        const INPUT: &str = "#ah\r\n-00003159,00012989,1\r\n-00007920,00008779,0\r\n";
+        let (rest_input, changes) = station_changes(INPUT).expect("failed to parse");
+
+        assert!(rest_input.is_empty());
+        assert_eq!(
+            changes,
+            vec![
+                Change {
+                    station: "ah".to_string(),
+                    from_service: 3159,
+                    to_service: 12989,
+                    possibility: ChangePossibility::Possible,
+                },
+                Change {
+                    station: "ah".to_string(),
+                    from_service: 7920,
+                    to_service: 8779,
+                    possibility: ChangePossibility::NotPossible,
+                },
+            ]
+        )
+    }
+
+    #[test]
+    fn it_parses_change_file() {
+        let input = read_iso_8859_1_file("./example/changes.dat").unwrap();
+        let (rest_input, changes) = change_file(&input).unwrap();
+
+        assert!(rest_input.is_empty());
+        assert_eq!(changes.data.len(), 68675);
+    }
+}
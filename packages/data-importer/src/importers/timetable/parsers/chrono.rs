@@ -31,3 +31,13 @@ pub fn time_string(input: &str) -> IResult<&str, NaiveTime> {
         NaiveTime::from_hms_opt(hour % 24, minute, 0).unwrap(),
     ))
 }
+
+/// A time of a service, which is written as 24:00 and later past midnight of its running date.
+/// Returns the time of day along with the number of days past the running date.
+pub fn service_time_string(input: &str) -> IResult<&str, (NaiveTime, u32)> {
+    let (_, hour) =
+        map_res(take_while_m_n(2, 2, AsChar::is_dec_digit), u32::from_str).parse(input)?;
+    let (input, time) = time_string(input)?;
+
+    Ok((input, (time, hour / 24)))
+}
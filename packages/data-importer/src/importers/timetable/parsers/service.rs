@@ -1,12 +1,11 @@
 use crate::importers::timetable::parsers::service::station_event::StationEventType;
+use anyhow::{Context, Result, bail};
 use attribute::Attribute;
+use chrono::{NaiveTime, Timelike};
 use identification::ServiceIdentification;
 use number::ServiceNumber;
 use platform_info::PlatformInfo;
 use station_event::StationEvent;
-use std::iter::Filter;
-use std::ops::Index;
-use std::slice::Iter;
 use transport_mode::TransportMode;
 use validity::Validity;
 
@@ -18,6 +17,26 @@ pub mod station_event;
 pub mod transport_mode;
 pub mod validity;
 
+fn event_minutes(
+    station_events: &[(StationEvent, Option<PlatformInfo>)],
+) -> Vec<(Option<u32>, Option<u32>)> {
+    const MINUTES_PER_DAY: u32 = 24 * 60;
+
+    let minutes = |time: Option<NaiveTime>, day: u32| {
+        time.map(|time| day * MINUTES_PER_DAY + time.hour() * 60 + time.minute())
+    };
+
+    station_events
+        .iter()
+        .map(|(event, _)| {
+            (
+                minutes(event.arrival_time, event.arrival_day),
+                minutes(event.departure_time, event.departure_day),
+            )
+        })
+        .collect()
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Service {
     pub identification: ServiceIdentification,
@@ -43,16 +62,29 @@ impl Service {
         Some((event.clone(), platform_info.clone().unwrap()))
     }
 
-    pub fn split_legs(&self) -> Vec<ServiceLeg> {
+    pub fn num_stops(&self) -> u32 {
+        self.station_events
+            .iter()
+            .filter(|(event, _)| event.stop_type != StationEventType::Passage)
+            .count() as u32
+    }
+
+    /// Arrival and departure of every station event in minutes after midnight of the running
+    /// date, so times past midnight are 24:00 and later.
+    pub fn event_minutes(&self) -> Vec<(Option<u32>, Option<u32>)> {
+        event_minutes(&self.station_events)
+    }
+
+    pub fn split_legs(&self) -> Result<Vec<ServiceLeg>> {
         if self.service_number.len() == 1 {
-            return vec![ServiceLeg {
+            return Ok(vec![ServiceLeg {
                 service_identification: self.identification.clone(),
                 service_number: self.service_number[0].clone(),
                 validity: self.validity.clone(),
                 transport_mode: self.transport_mode.clone(),
                 attributes: self.attributes.clone(),
                 station_events: self.station_events.clone(),
-            }];
+            }]);
         }
 
         if self.service_number.len() == 2 {
@@ -60,15 +92,20 @@ impl Service {
                 .station_events
                 .iter()
                 .filter(|(e, _)| e.stop_type != StationEventType::Passage)
-                .collect::<Vec<_>>()[self.service_number[0].last_stop as usize - 1];
+                .nth((self.service_number[0].last_stop as usize).saturating_sub(1))
+                .context("service number ends at a stop that does not exist")?;
 
             let split_index = self
                 .station_events
                 .iter()
                 .position(|(e, _)| e.station == *split_stop.0.station)
-                .unwrap();
+                .context("split stop not found in station events")?;
 
             let split_event = self.station_events[split_index].clone();
+            if split_event.0.arrival_time.is_none() || split_event.0.departure_time.is_none() {
+                bail!("service cannot be split at a stop without arrival and departure time");
+            }
+
             let new_arrival = (split_event.clone().0.into_arrival(), split_event.clone().1);
             let new_departure = (
                 split_event.clone().0.into_departure(),
@@ -81,7 +118,7 @@ impl Service {
             let mut new_events_leg_2 = self.station_events[split_index + 1..].to_vec();
             new_events_leg_2.insert(0, new_departure);
 
-            return vec![
+            return Ok(vec![
                 ServiceLeg {
                     service_identification: self.identification.clone(),
                     service_number: self.service_number[0].clone(),
@@ -98,10 +135,13 @@ impl Service {
                     attributes: self.attributes.clone(),
                     station_events: new_events_leg_2,
                 },
-            ];
+            ]);
         }
 
-        panic!();
+        bail!(
+            "unsupported number of service numbers: {}",
+            self.service_number.len()
+        )
     }
 }
 
@@ -116,6 +156,11 @@ pub struct ServiceLeg {
 }
 
 impl ServiceLeg {
+    /// The number this train is known by, falling back to the variant for services without one
+    pub fn train_number(&self) -> Option<String> {
+        self.service_number.train_number()
+    }
+
     pub fn stops(&self) -> impl Iterator<Item = &(StationEvent, Option<PlatformInfo>)> {
         self.station_events
             .iter()
@@ -126,6 +171,12 @@ impl ServiceLeg {
         self.stops().count() as u32
     }
 
+    /// Arrival and departure of every station event in minutes after midnight of the running
+    /// date, so times past midnight are 24:00 and later.
+    pub fn event_minutes(&self) -> Vec<(Option<u32>, Option<u32>)> {
+        event_minutes(&self.station_events)
+    }
+
     pub fn stop_number(&self, event: &StationEvent) -> Option<u32> {
         if event.stop_type == StationEventType::Passage {
             return None;
@@ -136,3 +187,73 @@ impl ServiceLeg {
             .map(|pos| pos as u32)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::importers::timetable::parsers::timetable::timetable_file;
+
+    #[test]
+    fn it_moves_times_past_midnight_to_the_next_day() {
+        const INPUT: &str = "@100,07042025,13122025,0070,IFF Standaard uit RIF\r
+#00000001\r
+%100,04084,      ,001,004,                              \r
+-00001,000,999\r
+&SPR ,001,004\r
+>gd     ,2348\r
+;gdg\r
++wd     ,2359,2401\r
+<ut     ,2420\r
+";
+
+        let (_, timetable) = timetable_file(INPUT).expect("failed to parse");
+        let legs = timetable.data[0].split_legs().unwrap();
+
+        assert_eq!(
+            legs[0].event_minutes(),
+            vec![
+                (None, Some(23 * 60 + 48)),
+                (None, None),
+                (Some(23 * 60 + 59), Some(24 * 60 + 1)),
+                (Some(24 * 60 + 20), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_keeps_legs_starting_past_midnight_on_the_next_day() {
+        const INPUT: &str = "@100,07042025,13122025,0070,IFF Standaard uit RIF\r
+#00000001\r
+%100,04084,      ,001,002,                              \r
+%100,04085,      ,002,003,                              \r
+-00001,000,999\r
+&SPR ,001,003\r
+>gd     ,2348\r
++wd     ,2402,2405\r
+<ut     ,2420\r
+#00000002\r
+%100,04086,      ,001,002,                              \r
+-00001,000,999\r
+&SPR ,001,002\r
+>ut     ,2410\r
+<gd     ,2440\r
+";
+
+        let (_, timetable) = timetable_file(INPUT).expect("failed to parse");
+
+        let legs = timetable.data[0].split_legs().unwrap();
+        assert_eq!(
+            legs[0].event_minutes(),
+            vec![(None, Some(23 * 60 + 48)), (Some(24 * 60 + 2), None)]
+        );
+        assert_eq!(
+            legs[1].event_minutes(),
+            vec![(None, Some(24 * 60 + 5)), (Some(24 * 60 + 20), None)]
+        );
+
+        let legs = timetable.data[1].split_legs().unwrap();
+        assert_eq!(
+            legs[0].event_minutes(),
+            vec![(None, Some(24 * 60 + 10)), (Some(24 * 60 + 40), None)]
+        );
+    }
+}
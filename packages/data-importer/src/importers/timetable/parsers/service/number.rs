@@ -18,6 +18,15 @@ pub struct ServiceNumber {
     pub name: Option<String>,
 }
 
+impl ServiceNumber {
+    /// The number this train is known by, falling back to the variant for services without one
+    pub fn train_number(&self) -> Option<String> {
+        (self.service_number != 0)
+            .then_some(self.service_number.to_string())
+            .or(self.variant.clone())
+    }
+}
+
 pub fn service_number(input: &str) -> IResult<&str, ServiceNumber> {
     let (input, _) = tag("%")(input)?;
     let (input, company_number) = map_res(
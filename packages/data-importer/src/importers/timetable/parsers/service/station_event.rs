@@ -8,7 +8,7 @@ use nom::{
 };
 use std::fmt::Display;
 
-use crate::importers::timetable::parsers::{chrono::time_string, utils::is_eol};
+use crate::importers::timetable::parsers::{chrono::service_time_string, utils::is_eol};
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum StationEventType {
@@ -52,6 +52,9 @@ pub struct StationEvent {
 
     pub arrival_time: Option<NaiveTime>,
     pub departure_time: Option<NaiveTime>,
+    /// Days past the running date of the service, for times written as 24:00 and later
+    pub arrival_day: u32,
+    pub departure_day: u32,
 }
 
 impl StationEvent {
@@ -61,8 +64,10 @@ impl StationEvent {
         StationEvent {
             stop_type: StationEventType::Arrival,
             station: self.station.clone(),
-            arrival_time: self.arrival_time.clone(),
+            arrival_time: self.arrival_time,
             departure_time: None,
+            arrival_day: self.arrival_day,
+            departure_day: 0,
         }
     }
 
@@ -73,7 +78,9 @@ impl StationEvent {
             stop_type: StationEventType::Departure,
             station: self.station.clone(),
             arrival_time: None,
-            departure_time: self.departure_time.clone(),
+            departure_time: self.departure_time,
+            arrival_day: 0,
+            departure_day: self.departure_day,
         }
     }
 }
@@ -82,7 +89,8 @@ pub fn station_event(input: &str) -> IResult<&str, StationEvent> {
     fn departure(input: &str) -> IResult<&str, StationEvent> {
         let (input, _) = tag(">")(input)?;
         let (input, station) = terminated(take_until(","), char(',')).parse(input)?;
-        let (input, departure_time) = terminated(time_string, line_ending).parse(input)?;
+        let (input, (departure_time, departure_day)) =
+            terminated(service_time_string, line_ending).parse(input)?;
 
         Ok((
             input,
@@ -91,6 +99,8 @@ pub fn station_event(input: &str) -> IResult<&str, StationEvent> {
                 station: station.trim().to_string(),
                 arrival_time: None,
                 departure_time: Some(departure_time),
+                arrival_day: 0,
+                departure_day,
             },
         ))
     }
@@ -106,6 +116,8 @@ pub fn station_event(input: &str) -> IResult<&str, StationEvent> {
                 station: station.trim().to_string(),
                 arrival_time: None,
                 departure_time: None,
+                arrival_day: 0,
+                departure_day: 0,
             },
         ))
     }
@@ -113,7 +125,7 @@ pub fn station_event(input: &str) -> IResult<&str, StationEvent> {
     fn short_stop(input: &str) -> IResult<&str, StationEvent> {
         let (input, _) = tag(".")(input)?;
         let (input, station) = terminated(take_until(","), char(',')).parse(input)?;
-        let (input, time) = terminated(time_string, line_ending).parse(input)?;
+        let (input, (time, day)) = terminated(service_time_string, line_ending).parse(input)?;
 
         Ok((
             input,
@@ -122,6 +134,8 @@ pub fn station_event(input: &str) -> IResult<&str, StationEvent> {
                 station: station.trim().to_string(),
                 arrival_time: Some(time),
                 departure_time: Some(time),
+                arrival_day: day,
+                departure_day: day,
             },
         ))
     }
@@ -130,8 +144,10 @@ pub fn station_event(input: &str) -> IResult<&str, StationEvent> {
         let (input, _) = tag("+")(input)?;
         let (input, station) = terminated(take_until(","), char(',')).parse(input)?;
 
-        let (input, arrival_time) = terminated(time_string, char(',')).parse(input)?;
-        let (input, departure_time) = terminated(time_string, line_ending).parse(input)?;
+        let (input, (arrival_time, arrival_day)) =
+            terminated(service_time_string, char(',')).parse(input)?;
+        let (input, (departure_time, departure_day)) =
+            terminated(service_time_string, line_ending).parse(input)?;
 
         Ok((
             input,
@@ -140,6 +156,8 @@ pub fn station_event(input: &str) -> IResult<&str, StationEvent> {
                 station: station.trim().to_string(),
                 arrival_time: Some(arrival_time),
                 departure_time: Some(departure_time),
+                arrival_day,
+                departure_day,
             },
         ))
     }
@@ -147,7 +165,8 @@ pub fn station_event(input: &str) -> IResult<&str, StationEvent> {
     fn arrival(input: &str) -> IResult<&str, StationEvent> {
         let (input, _) = tag("<")(input)?;
         let (input, station) = terminated(take_until(","), char(',')).parse(input)?;
-        let (input, arrival_time) = terminated(time_string, line_ending).parse(input)?;
+        let (input, (arrival_time, arrival_day)) =
+            terminated(service_time_string, line_ending).parse(input)?;
 
         Ok((
             input,
@@ -156,6 +175,8 @@ pub fn station_event(input: &str) -> IResult<&str, StationEvent> {
                 station: station.trim().to_string(),
                 arrival_time: Some(arrival_time),
                 departure_time: None,
+                arrival_day,
+                departure_day: 0,
             },
         ))
     }
@@ -189,6 +210,8 @@ mod test {
                     station: "alm".to_string(),
                     arrival_time: None,
                     departure_time: Some(NaiveTime::from_hms_opt(19, 31, 0).unwrap()),
+                    arrival_day: 0,
+                    departure_day: 0,
                 }
             )
         )
@@ -208,6 +231,8 @@ mod test {
                     station: "almm".to_string(),
                     arrival_time: None,
                     departure_time: None,
+                    arrival_day: 0,
+                    departure_day: 0,
                 }
             )
         )
@@ -227,6 +252,8 @@ mod test {
                     station: "ass".to_string(),
                     arrival_time: Some(NaiveTime::from_hms_opt(19, 59, 0).unwrap()),
                     departure_time: Some(NaiveTime::from_hms_opt(19, 59, 0).unwrap()),
+                    arrival_day: 0,
+                    departure_day: 0,
                 }
             )
         )
@@ -246,6 +273,8 @@ mod test {
                     station: "asd".to_string(),
                     arrival_time: Some(NaiveTime::from_hms_opt(19, 51, 0).unwrap()),
                     departure_time: Some(NaiveTime::from_hms_opt(19, 53, 0).unwrap()),
+                    arrival_day: 0,
+                    departure_day: 0,
                 }
             )
         )
@@ -265,6 +294,29 @@ mod test {
                     station: "ekz".to_string(),
                     arrival_time: Some(NaiveTime::from_hms_opt(20, 53, 0).unwrap()),
                     departure_time: None,
+                    arrival_day: 0,
+                    departure_day: 0,
+                }
+            )
+        )
+    }
+
+    #[test]
+    fn it_parses_times_past_midnight() {
+        const INPUT: &str = "+asd    ,2359,2405\r\n";
+        let result = station_event(INPUT).expect("failed to parse");
+
+        assert_eq!(
+            result,
+            (
+                "",
+                StationEvent {
+                    stop_type: StationEventType::LongerStop,
+                    station: "asd".to_string(),
+                    arrival_time: Some(NaiveTime::from_hms_opt(23, 59, 0).unwrap()),
+                    departure_time: Some(NaiveTime::from_hms_opt(0, 5, 0).unwrap()),
+                    arrival_day: 0,
+                    departure_day: 1,
                 }
             )
         )
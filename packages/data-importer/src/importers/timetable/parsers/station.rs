@@ -20,6 +20,8 @@ pub struct Station {
     pub country: String,
     pub is_interchange: bool,
     pub layover_minimum_minutes: u8,
+    /// Rijksdriehoek coordinates in metres, not set for (most) stations outside of the Netherlands
+    pub rd_coordinates: Option<(u32, u32)>,
 }
 
 pub fn station(input: &str) -> IResult<&str, Station> {
@@ -37,7 +39,12 @@ pub fn station(input: &str) -> IResult<&str, Station> {
     let (input, country) = terminated(take_until(","), char(',')).parse(input)?;
     let country = country.trim().to_string();
 
-    let (input, _) = many_m_n(4, 4, terminated(take_until(","), char(','))).parse(input)?;
+    let (input, _) = many_m_n(2, 2, terminated(take_until(","), char(','))).parse(input)?;
+
+    let (input, x) = terminated(map_res(take_until(","), u32::from_str), char(',')).parse(input)?;
+    let (input, y) = terminated(map_res(take_until(","), u32::from_str), char(',')).parse(input)?;
+    // coordinates are given in units of 10 metres
+    let rd_coordinates = (x != 0 || y != 0).then_some((x * 10, y * 10));
 
     let (input, name) = terminated(take_till(is_eol), line_ending).parse(input)?;
     let name = name.trim().to_string();
@@ -50,6 +57,7 @@ pub fn station(input: &str) -> IResult<&str, Station> {
             country,
             is_interchange,
             layover_minimum_minutes,
+            rd_coordinates,
         },
     ))
 }
@@ -87,6 +95,7 @@ mod test {
                 country: "NL".to_string(),
                 is_interchange: true,
                 layover_minimum_minutes: 2,
+                rd_coordinates: Some((127010, 476830)),
             }
         )
     }
@@ -0,0 +1,648 @@
+use crate::importers::timetable::parsers::footnote::Footnote;
+use crate::importers::timetable::parsers::service::Service;
+use crate::importers::timetable::parsers::service::station_event::StationEventType;
+use crate::importers::timetable::quality::ReportFormat;
+use crate::importers::timetable::transfers::TransferModel;
+use crate::importers::timetable::{Delivery, load_delivery};
+use anyhow::{Result, anyhow, bail};
+use chrono::{Days, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Timelike};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+const MINUTES_PER_DAY: i32 = 24 * 60;
+
+/// A stop of a trip, in minutes since midnight of the planning date. Services of the day before
+/// that run past midnight have negative times for the stops before it.
+#[derive(Debug)]
+struct TripStop {
+    station: usize,
+    arrival: Option<i32>,
+    departure: Option<i32>,
+    arrival_platform: Option<String>,
+    departure_platform: Option<String>,
+    train_number: Option<String>,
+}
+
+/// A service running on the planning date. Passages are left out, as passengers can neither board
+/// nor alight there.
+#[derive(Debug)]
+struct Trip {
+    service: u32,
+    transport_mode: String,
+    stops: Vec<TripStop>,
+}
+
+/// Trips that call at the same stations in the same order.
+#[derive(Debug)]
+struct Route {
+    stations: Vec<usize>,
+    /// Sorted by their departure from the first station
+    trips: Vec<usize>,
+}
+
+/// How a station is reached in a round, with the round it was first reached in, as labels are
+/// carried over to later rounds.
+#[derive(Debug, Clone, Copy)]
+enum Label {
+    Origin {
+        departure: i32,
+    },
+    Ride {
+        arrival: i32,
+        round: usize,
+        trip: usize,
+        boarded: usize,
+        alighted: usize,
+    },
+}
+
+impl Label {
+    fn arrival(&self) -> i32 {
+        match self {
+            Label::Origin { departure } => *departure,
+            Label::Ride { arrival, .. } => *arrival,
+        }
+    }
+}
+
+/// The timetable of one day, laid out for RAPTOR.
+#[derive(Debug)]
+pub struct Network {
+    date: NaiveDate,
+    stations: Vec<String>,
+    station_index: HashMap<String, usize>,
+    trips: Vec<Trip>,
+    routes: Vec<Route>,
+    /// Routes calling at every station, with the position of the station in the route
+    routes_by_station: Vec<Vec<(usize, usize)>>,
+    transfers: TransferModel,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PlannedLeg {
+    pub train_number: Option<String>,
+    pub transport_mode: String,
+    pub from: String,
+    pub departure: NaiveDateTime,
+    pub departure_platform: Option<String>,
+    pub to: String,
+    pub arrival: NaiveDateTime,
+    pub arrival_platform: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Itinerary {
+    pub departure: NaiveDateTime,
+    pub arrival: NaiveDateTime,
+    pub transfers: usize,
+    pub legs: Vec<PlannedLeg>,
+}
+
+impl Display for Itinerary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let platform = |platform: &Option<String>| {
+            platform
+                .as_ref()
+                .map_or(String::new(), |platform| format!(" ({platform})"))
+        };
+
+        writeln!(
+            f,
+            "{} -> {}, {} transfer(s)",
+            self.departure.format("%Y-%m-%d %H:%M"),
+            self.arrival.format("%H:%M"),
+            self.transfers
+        )?;
+        for leg in &self.legs {
+            writeln!(
+                f,
+                "  {} {}: {} {}{} -> {} {}{}",
+                leg.transport_mode,
+                leg.train_number.as_deref().unwrap_or("-"),
+                leg.departure.format("%H:%M"),
+                leg.from,
+                platform(&leg.departure_platform),
+                leg.arrival.format("%H:%M"),
+                leg.to,
+                platform(&leg.arrival_platform),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+fn minutes(time: &NaiveTime) -> i32 {
+    (time.hour() * 60 + time.minute()) as i32
+}
+
+fn platform(platform: &str) -> Option<String> {
+    (!platform.is_empty()).then(|| platform.to_string())
+}
+
+impl Network {
+    /// Lays out the services of `delivery` running on `date`, and those of the day before that run
+    /// past midnight.
+    pub fn new(delivery: &Delivery, date: NaiveDate) -> Self {
+        let identification = &delivery.timetable.identification;
+        let stations = delivery
+            .stations
+            .data
+            .iter()
+            .map(|station| station.code.to_lowercase())
+            .collect::<Vec<_>>();
+        let station_index = stations
+            .iter()
+            .enumerate()
+            .map(|(index, code)| (code.clone(), index))
+            .collect::<HashMap<_, _>>();
+
+        let mut valid = HashMap::new();
+        let mut runs_on = |footnote: u32, date: NaiveDate| {
+            *valid.entry((footnote, date)).or_insert_with(|| {
+                let always_valid;
+                let footnote = match footnote {
+                    0 => {
+                        always_valid = Footnote::always_valid(identification);
+                        &always_valid
+                    }
+                    _ => match delivery.footnotes.get_by_id(footnote) {
+                        Some(footnote) => footnote,
+                        None => return false,
+                    },
+                };
+                footnote.is_valid_on_date(&date, identification)
+            })
+        };
+
+        let mut trips = Vec::new();
+        let previous_day = date.checked_sub_days(Days::new(1));
+        for service in &delivery.timetable.data {
+            let footnote = service.validity.footnote;
+            if runs_on(footnote, date) {
+                trips.extend(Self::trip(service, &station_index, 0));
+            }
+            if previous_day.is_some_and(|previous_day| runs_on(footnote, previous_day)) {
+                trips.extend(
+                    Self::trip(service, &station_index, -MINUTES_PER_DAY)
+                        .filter(|trip| trip.stops.iter().any(|stop| stop.arrival >= Some(0))),
+                );
+            }
+        }
+
+        let mut routes_by_pattern = BTreeMap::<Vec<usize>, Vec<usize>>::new();
+        for (index, trip) in trips.iter().enumerate() {
+            let pattern = trip.stops.iter().map(|stop| stop.station).collect();
+            routes_by_pattern.entry(pattern).or_default().push(index);
+        }
+
+        let mut routes = Vec::with_capacity(routes_by_pattern.len());
+        let mut routes_by_station = vec![Vec::new(); stations.len()];
+        for (stations, mut route_trips) in routes_by_pattern {
+            route_trips.sort_by_key(|trip| trips[*trip].stops[0].departure);
+            for (position, station) in stations.iter().enumerate() {
+                routes_by_station[*station].push((routes.len(), position));
+            }
+            routes.push(Route {
+                stations,
+                trips: route_trips,
+            });
+        }
+
+        Network {
+            date,
+            stations,
+            station_index,
+            trips,
+            routes,
+            routes_by_station,
+            transfers: TransferModel::from_delivery(delivery),
+        }
+    }
+
+    /// The stops of a service, in minutes since midnight of its running date shifted by `offset`.
+    fn trip(
+        service: &Service,
+        station_index: &HashMap<String, usize>,
+        offset: i32,
+    ) -> Option<Trip> {
+        let mut stops = Vec::new();
+        let mut stop_index = 0;
+        let time = |time: Option<NaiveTime>, day: u32| {
+            time.map(|time| offset + day as i32 * MINUTES_PER_DAY + minutes(&time))
+        };
+
+        for (event, platform_info) in &service.station_events {
+            if event.stop_type == StationEventType::Passage {
+                continue;
+            }
+            stop_index += 1;
+
+            let arrival = time(event.arrival_time, event.arrival_day);
+            let departure = time(event.departure_time, event.departure_day);
+            // stations outside of stations.dat can't be planned from or to
+            let Some(&station) = station_index.get(&event.station.to_lowercase()) else {
+                continue;
+            };
+            let number = service
+                .service_number
+                .iter()
+                .find(|number| number.first_stop <= stop_index && stop_index <= number.last_stop)
+                .or(service.service_number.first());
+
+            stops.push(TripStop {
+                station,
+                arrival,
+                departure,
+                arrival_platform: platform_info
+                    .as_ref()
+                    .and_then(|info| platform(&info.arrival_platform)),
+                departure_platform: platform_info
+                    .as_ref()
+                    .and_then(|info| platform(&info.departure_platform)),
+                train_number: number.and_then(|number| {
+                    (number.service_number != 0)
+                        .then(|| number.service_number.to_string())
+                        .or(number.variant.clone())
+                }),
+            });
+        }
+
+        (stops.len() > 1).then(|| Trip {
+            service: service.identification.0,
+            transport_mode: service.transport_mode.code.clone(),
+            stops,
+        })
+    }
+
+    fn station(&self, code: &str) -> Result<usize> {
+        self.station_index
+            .get(&code.to_lowercase())
+            .copied()
+            .ok_or_else(|| anyhow!("! unknown station '{code}'"))
+    }
+
+    fn time(&self, minutes: i32) -> NaiveDateTime {
+        self.date.and_time(NaiveTime::MIN) + TimeDelta::minutes(minutes as i64)
+    }
+
+    /// When a trip can be boarded at a station reached by `label`, or `None` if passengers can't
+    /// change to it there. Staying on the same trip is not a transfer.
+    fn ready_for(&self, label: &Label, station: usize, trip: usize) -> Option<i32> {
+        let Label::Ride {
+            arrival,
+            trip: from,
+            ..
+        } = label
+        else {
+            return Some(label.arrival());
+        };
+
+        // the copies of a service on the day before and on the planning date are different trips
+        if *from == trip {
+            return Some(*arrival);
+        }
+
+        let (from, to) = (self.trips[*from].service, self.trips[trip].service);
+        let transfer = self
+            .transfers
+            .minimum_transfer_time(&self.stations[station], from, to)?;
+        Some(arrival + i32::from(transfer.minutes))
+    }
+
+    /// The Pareto-optimal itineraries from `from` to `to` departing at or after `departure`, with
+    /// at most `max_transfers` transfers: every itinerary arrives earlier than those with fewer
+    /// transfers.
+    pub fn plan(
+        &self,
+        from: &str,
+        to: &str,
+        departure: NaiveTime,
+        max_transfers: usize,
+    ) -> Result<Vec<Itinerary>> {
+        let origin = self.station(from)?;
+        let target = self.station(to)?;
+        if origin == target {
+            bail!("! origin and destination are the same station");
+        }
+
+        let rounds = max_transfers + 1;
+        let mut labels: Vec<Vec<Option<Label>>> = vec![vec![None; self.stations.len()]];
+        let mut best = vec![i32::MAX; self.stations.len()];
+
+        let departure = minutes(&departure);
+        labels[0][origin] = Some(Label::Origin { departure });
+        best[origin] = departure;
+        let mut marked = vec![origin];
+
+        for round in 1..=rounds {
+            let previous = labels[round - 1].clone();
+            let mut current = previous.clone();
+
+            let mut queue = BTreeMap::<usize, usize>::new();
+            for station in marked.drain(..) {
+                for (route, position) in &self.routes_by_station[station] {
+                    let start = queue.entry(*route).or_insert(*position);
+                    *start = (*start).min(*position);
+                }
+            }
+
+            let mut improved = vec![false; self.stations.len()];
+            for (route, start) in queue {
+                let route = &self.routes[route];
+                let mut boarded: Option<(usize, usize)> = None;
+
+                for position in start..route.stations.len() {
+                    let station = route.stations[position];
+
+                    // only worth alighting if it improves on this station and the target
+                    let alighting = boarded.and_then(|(trip, boarded_at)| {
+                        let arrival = self.trips[trip].stops[position].arrival?;
+                        (arrival < best[station].min(best[target]))
+                            .then_some((trip, boarded_at, arrival))
+                    });
+                    if let Some((trip, boarded_at, arrival)) = alighting {
+                        current[station] = Some(Label::Ride {
+                            arrival,
+                            round,
+                            trip,
+                            boarded: boarded_at,
+                            alighted: position,
+                        });
+                        best[station] = arrival;
+                        improved[station] = true;
+                    }
+
+                    let Some(label) = &previous[station] else {
+                        continue;
+                    };
+                    let departs = |trip: usize| self.trips[trip].stops[position].departure;
+                    let earlier = route
+                        .trips
+                        .iter()
+                        .copied()
+                        .filter(|trip| {
+                            departs(*trip)
+                                .zip(self.ready_for(label, station, *trip))
+                                .is_some_and(|(departure, ready)| {
+                                    departure >= ready && departure >= 0
+                                })
+                        })
+                        .min_by_key(|trip| departs(*trip))
+                        .filter(|trip| {
+                            boarded.is_none_or(|(boarded, _)| departs(*trip) < departs(boarded))
+                        });
+                    if let Some(trip) = earlier {
+                        boarded = Some((trip, position));
+                    }
+                }
+            }
+
+            marked = (0..self.stations.len())
+                .filter(|station| improved[*station])
+                .collect();
+            labels.push(current);
+            if marked.is_empty() {
+                break;
+            }
+        }
+
+        let mut itineraries = Vec::new();
+        for (round, round_labels) in labels.iter().enumerate().skip(1) {
+            // carried over from an earlier round, so not an improvement
+            if matches!(round_labels[target], Some(Label::Ride { round: reached, .. }) if reached == round)
+            {
+                itineraries.push(self.itinerary(&labels, round, target));
+            }
+        }
+
+        Ok(itineraries)
+    }
+
+    fn itinerary(&self, labels: &[Vec<Option<Label>>], round: usize, target: usize) -> Itinerary {
+        let mut legs = Vec::new();
+        let mut label = labels[round][target];
+
+        while let Some(Label::Ride {
+            round,
+            trip,
+            boarded,
+            alighted,
+            ..
+        }) = label
+        {
+            let trip_stops = &self.trips[trip].stops;
+            let (from, to) = (&trip_stops[boarded], &trip_stops[alighted]);
+            legs.push(PlannedLeg {
+                train_number: from.train_number.clone(),
+                transport_mode: self.trips[trip].transport_mode.clone(),
+                from: self.stations[from.station].clone(),
+                departure: self.time(from.departure.unwrap_or_default()),
+                departure_platform: from.departure_platform.clone(),
+                to: self.stations[to.station].clone(),
+                arrival: self.time(to.arrival.unwrap_or_default()),
+                arrival_platform: to.arrival_platform.clone(),
+            });
+            label = labels[round - 1][from.station];
+        }
+        legs.reverse();
+
+        Itinerary {
+            departure: legs[0].departure,
+            arrival: legs[legs.len() - 1].arrival,
+            transfers: legs.len() - 1,
+            legs,
+        }
+    }
+}
+
+/// Plans journeys over the delivery at `input_path`, without a database.
+pub fn plan(
+    input_path: String,
+    from: String,
+    to: String,
+    departure: String,
+    max_transfers: usize,
+    format: ReportFormat,
+) -> Result<()> {
+    let departure = NaiveDateTime::parse_from_str(&departure, "%Y-%m-%d %H:%M")
+        .map_err(|_| anyhow!("! departure should be formatted as YYYY-MM-DD HH:MM"))?;
+
+    let delivery = load_delivery(&PathBuf::from(input_path))?;
+    let network = Network::new(&delivery, departure.date());
+    let itineraries = network.plan(&from, &to, departure.time(), max_transfers)?;
+
+    match format {
+        ReportFormat::Text => {
+            if itineraries.is_empty() {
+                println!("no itineraries found");
+            }
+            for itinerary in &itineraries {
+                print!("{itinerary}");
+            }
+        }
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&itineraries)?),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::importers::timetable::test_support::{IDENTIFICATION, test_delivery};
+
+    const STATIONS: &str = "1,rtd    ,03,03,NL  ,0000,  ,000000,000000,Rotterdam Centraal\r
+1,gd     ,02,02,NL  ,0000,  ,000000,000000,Gouda\r
+1,ut     ,03,03,NL  ,0000,  ,000000,000000,Utrecht Centraal\r
+";
+
+    fn delivery(changes: &str) -> Delivery {
+        test_delivery(
+            IDENTIFICATION,
+            "#00000001\r
+%100,01234,      ,001,003,                              \r
+-00000,000,999\r
+&SPR ,001,003\r
+>rtd    ,1000\r
+.gd     ,1030\r
+<ut     ,1100\r
+#00000002\r
+%100,02345,      ,001,002,                              \r
+-00000,000,999\r
+&IC  ,001,002\r
+>rtd    ,1005\r
+?3   ,3   ,00000\r
+<gd     ,1020\r
+?8   ,8   ,00000\r
+#00000003\r
+%100,03456,      ,001,002,                              \r
+-00000,000,999\r
+&IC  ,001,002\r
+>gd     ,1022\r
+?9   ,9   ,00000\r
+<ut     ,1040\r
+?18  ,18  ,00000\r
+#00000004\r
+%100,04567,      ,001,002,                              \r
+-00002,000,999\r
+&IC  ,001,002\r
+>rtd    ,1001\r
+;gd     \r
+<ut     ,1035\r
+",
+            "#00002\r\n00100\r\n",
+            "",
+            STATIONS,
+            changes,
+        )
+    }
+
+    fn plan(delivery: &Delivery, date: &str, from: &str) -> Vec<Itinerary> {
+        let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap();
+        Network::new(delivery, date)
+            .plan(from, "ut", NaiveTime::from_hms_opt(10, 0, 0).unwrap(), 2)
+            .unwrap()
+    }
+
+    fn summary(itineraries: &[Itinerary]) -> Vec<(String, usize)> {
+        itineraries
+            .iter()
+            .map(|itinerary| {
+                (
+                    itinerary.arrival.format("%H:%M").to_string(),
+                    itinerary.transfers,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_plans_pareto_optimal_itineraries() {
+        let itineraries = plan(&delivery(""), "2025-06-01", "rtd");
+
+        assert_eq!(
+            summary(&itineraries),
+            vec![("11:00".to_string(), 0), ("10:40".to_string(), 1)]
+        );
+        assert_eq!(
+            itineraries[1].legs[1],
+            PlannedLeg {
+                train_number: Some("3456".to_string()),
+                transport_mode: "IC".to_string(),
+                from: "gd".to_string(),
+                departure: NaiveDateTime::parse_from_str("2025-06-01 10:22", "%Y-%m-%d %H:%M")
+                    .unwrap(),
+                departure_platform: Some("9".to_string()),
+                to: "ut".to_string(),
+                arrival: NaiveDateTime::parse_from_str("2025-06-01 10:40", "%Y-%m-%d %H:%M")
+                    .unwrap(),
+                arrival_platform: Some("18".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn it_respects_footnotes_and_passages() {
+        // only runs on the third day, and passes gd without stopping
+        assert_eq!(
+            summary(&plan(&delivery(""), "2025-06-03", "rtd")),
+            vec![("10:35".to_string(), 0)]
+        );
+        assert_eq!(
+            summary(&plan(&delivery(""), "2025-06-03", "gd")),
+            vec![("10:40".to_string(), 0)]
+        );
+    }
+
+    #[test]
+    fn it_respects_transfer_times() {
+        let itineraries = plan(
+            &delivery("#gd\r\n-00000002,00000003,0\r\n"),
+            "2025-06-01",
+            "rtd",
+        );
+
+        assert_eq!(summary(&itineraries), vec![("11:00".to_string(), 0)]);
+    }
+
+    #[test]
+    fn it_transfers_between_copies_of_a_service_on_consecutive_days() {
+        let delivery = test_delivery(
+            IDENTIFICATION,
+            "#00000001\r
+%100,01234,      ,001,003,                              \r
+-00000,000,999\r
+&IC  ,001,003\r
+>rtd    ,2350\r
+.gd     ,2410\r
+<ut     ,2430\r
+",
+            "",
+            "",
+            STATIONS,
+            "",
+        );
+        let network = Network::new(&delivery, NaiveDate::from_ymd_opt(2025, 6, 2).unwrap());
+        let trip = |departure| {
+            (0..network.trips.len())
+                .find(|trip| network.trips[*trip].stops[0].departure == Some(departure))
+                .unwrap()
+        };
+        let (previous_day, same_day) = (trip(-10), trip(23 * 60 + 50));
+        let gd = network.station("gd").unwrap();
+        let label = Label::Ride {
+            arrival: 10,
+            round: 0,
+            trip: previous_day,
+            boarded: 0,
+            alighted: 1,
+        };
+
+        assert_eq!(network.ready_for(&label, gd, previous_day), Some(10));
+        // getting off and on the next day's train at gd takes the station's transfer time
+        assert_eq!(network.ready_for(&label, gd, same_day), Some(12));
+    }
+}
@@ -0,0 +1,383 @@
+use crate::db;
+use crate::importers::timetable::parsers::service::Service;
+use crate::importers::timetable::parsers::service::station_event::StationEventType;
+use crate::importers::timetable::{Delivery, load_delivery};
+use anyhow::Result;
+use chrono::NaiveTime;
+use clap::ValueEnum;
+use deadpool_postgres::Pool;
+use sea_query::{PostgresQueryBuilder, Query};
+use sea_query_postgres::PostgresBinder;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueCategory {
+    NonMonotonicTimes,
+    MissingPlatformInfo,
+    UnknownStation,
+    UnknownStationInDatabase,
+    AttributeOutOfRange,
+    FootnoteLengthMismatch,
+    UnknownCompany,
+}
+
+impl Display for IssueCategory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            IssueCategory::NonMonotonicTimes => "non_monotonic_times",
+            IssueCategory::MissingPlatformInfo => "missing_platform_info",
+            IssueCategory::UnknownStation => "unknown_station",
+            IssueCategory::UnknownStationInDatabase => "unknown_station_in_database",
+            IssueCategory::AttributeOutOfRange => "attribute_out_of_range",
+            IssueCategory::FootnoteLengthMismatch => "footnote_length_mismatch",
+            IssueCategory::UnknownCompany => "unknown_company",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Issue {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub footnote: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub station: Option<String>,
+    pub detail: String,
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(service) = self.service {
+            write!(f, "service {service} ")?;
+        }
+        if let Some(footnote) = self.footnote {
+            write!(f, "footnote {footnote} ")?;
+        }
+        if let Some(station) = &self.station {
+            write!(f, "at {station} ")?;
+        }
+        write!(f, "- {}", self.detail)
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct QualityReport {
+    pub version_number: String,
+    pub issues: BTreeMap<IssueCategory, BTreeSet<Issue>>,
+}
+
+impl QualityReport {
+    fn add(&mut self, category: IssueCategory, issue: Issue) {
+        self.issues.entry(category).or_default().insert(issue);
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("delivery {}\n", self.version_number);
+        for (category, issues) in &self.issues {
+            text.push_str(&format!("\n{category} ({})\n", issues.len()));
+            for issue in issues {
+                text.push_str(&format!("  {issue}\n"));
+            }
+        }
+
+        text
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ReportFormat {
+    Text,
+    Json,
+}
+
+fn check_service(report: &mut QualityReport, service: &Service, delivery: &Delivery) {
+    let service_id = service.identification.0;
+
+    // compared in minutes since the running date, as times past midnight are 24:00 and later
+    let mut previous: Option<(&str, NaiveTime, u32)> = None;
+    for ((event, platform_info), (arrival, departure)) in
+        service.station_events.iter().zip(service.event_minutes())
+    {
+        let times = [
+            event.arrival_time.zip(arrival),
+            event.departure_time.zip(departure),
+        ];
+        for (time, minutes) in times.into_iter().flatten() {
+            let went_backwards =
+                previous.filter(|(_, _, previous_minutes)| minutes < *previous_minutes);
+
+            if let Some((previous_station, previous_time, _)) = went_backwards {
+                report.add(
+                    IssueCategory::NonMonotonicTimes,
+                    Issue {
+                        service: Some(service_id),
+                        footnote: None,
+                        station: Some(event.station.clone()),
+                        detail: format!(
+                            "{} is before {} at {previous_station}",
+                            time.format("%H:%M"),
+                            previous_time.format("%H:%M"),
+                        ),
+                    },
+                );
+            }
+
+            previous = Some((event.station.as_str(), time, minutes));
+        }
+
+        if event.stop_type != StationEventType::Passage && platform_info.is_none() {
+            report.add(
+                IssueCategory::MissingPlatformInfo,
+                Issue {
+                    service: Some(service_id),
+                    footnote: None,
+                    station: Some(event.station.clone()),
+                    detail: format!("{} without platform info", event.stop_type),
+                },
+            );
+        }
+    }
+
+    let num_stops = service.num_stops();
+    for attribute in &service.attributes {
+        if attribute.first_stop > attribute.last_stop || attribute.last_stop > num_stops {
+            report.add(
+                IssueCategory::AttributeOutOfRange,
+                Issue {
+                    service: Some(service_id),
+                    footnote: None,
+                    station: None,
+                    detail: format!(
+                        "attribute {} covers stops {}-{}, service has {num_stops} stops",
+                        attribute.code, attribute.first_stop, attribute.last_stop
+                    ),
+                },
+            );
+        }
+    }
+
+    for service_number in &service.service_number {
+        if delivery
+            .companies
+            .get_by_id(service_number.company_number)
+            .is_none()
+        {
+            report.add(
+                IssueCategory::UnknownCompany,
+                Issue {
+                    service: Some(service_id),
+                    footnote: None,
+                    station: None,
+                    detail: format!(
+                        "company {} not in company.dat",
+                        service_number.company_number
+                    ),
+                },
+            );
+        }
+    }
+}
+
+fn check_stations(
+    report: &mut QualityReport,
+    delivery: &Delivery,
+    database_station_codes: Option<&HashSet<String>>,
+) {
+    let mut services_per_station: BTreeMap<&str, BTreeSet<u32>> = BTreeMap::new();
+    for service in &delivery.timetable.data {
+        for (event, _) in &service.station_events {
+            services_per_station
+                .entry(event.station.as_str())
+                .or_default()
+                .insert(service.identification.0);
+        }
+    }
+
+    let known_stations = delivery
+        .stations
+        .data
+        .iter()
+        .map(|station| station.code.as_str())
+        .collect::<HashSet<_>>();
+
+    for (station, services) in services_per_station {
+        let issue = Issue {
+            service: None,
+            footnote: None,
+            station: Some(station.to_string()),
+            detail: format!("used by {} services", services.len()),
+        };
+
+        if !known_stations.contains(station) {
+            report.add(IssueCategory::UnknownStation, issue.clone());
+        }
+
+        if database_station_codes.is_some_and(|codes| !codes.contains(station)) {
+            report.add(IssueCategory::UnknownStationInDatabase, issue);
+        }
+    }
+}
+
+pub fn check_delivery(
+    delivery: &Delivery,
+    database_station_codes: Option<&HashSet<String>>,
+) -> QualityReport {
+    let mut report = QualityReport {
+        version_number: delivery.timetable.identification.version_number.clone(),
+        ..Default::default()
+    };
+
+    for service in &delivery.timetable.data {
+        check_service(&mut report, service, delivery);
+    }
+
+    check_stations(&mut report, delivery, database_station_codes);
+
+    let days_valid = delivery.footnotes.identification.days_valid();
+    for footnote in &delivery.footnotes.data {
+        if footnote.vector.len() as u64 != days_valid {
+            report.add(
+                IssueCategory::FootnoteLengthMismatch,
+                Issue {
+                    service: None,
+                    footnote: Some(footnote.id),
+                    station: None,
+                    detail: format!(
+                        "covers {} days, delivery is valid for {days_valid} days",
+                        footnote.vector.len()
+                    ),
+                },
+            );
+        }
+    }
+
+    report
+}
+
+async fn load_station_codes(db: &Pool) -> Result<HashSet<String>> {
+    let client = db.get().await?;
+    let (sql, params) = Query::select()
+        .column(db::Station::Code)
+        .from(db::Station::Table)
+        .build_postgres(PostgresQueryBuilder);
+
+    let rows = client.query(sql.as_str(), &params.as_params()).await?;
+
+    Ok(rows.iter().map(|row| row.get::<_, String>(0)).collect())
+}
+
+/// Prints a report of semantic problems in the delivery at `input_path`. Station codes are also
+/// checked against the `station` table when a database is given.
+pub async fn report(input_path: String, format: ReportFormat, db: Option<Arc<Pool>>) -> Result<()> {
+    let delivery = load_delivery(&PathBuf::from(input_path))?;
+
+    let database_station_codes = match db {
+        Some(db) => Some(load_station_codes(&db).await?),
+        None => None,
+    };
+
+    let report = check_delivery(&delivery, database_station_codes.as_ref());
+    match format {
+        ReportFormat::Text => print!("{}", report.to_text()),
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::importers::timetable::test_support::{IDENTIFICATION, test_delivery};
+
+    #[test]
+    fn it_reports_quality_issues() {
+        let delivery = test_delivery(
+            IDENTIFICATION,
+            "#00000001\r
+%100,01234,      ,001,003,                              \r
+-00001,000,999\r
+&SPR ,001,003\r
+*ROL ,001,004,00000\r
+>rtd    ,2355\r
+?1    ,1    ,00001\r
+.gd     ,2350\r
+<ut     ,2405\r
+?3    ,3    ,00001\r
+#00000002\r
+%999,05678,      ,001,002,                              \r
+-00001,000,999\r
+&IC  ,001,002\r
+>rtd    ,1100\r
+?1    ,1    ,00001\r
+<xyz    ,1200\r
+?3    ,3    ,00001\r
+",
+            "#00001\r\n11001\r\n#00002\r\n110\r\n",
+            "100,NS        ,NS                            ,0000\r\n",
+            "1,rtd    ,03,03,NL  ,0000,  ,000000,000000,Rotterdam Centraal\r
+1,gd     ,02,02,NL  ,0000,  ,000000,000000,Gouda\r
+1,ut     ,03,03,NL  ,0000,  ,000000,000000,Utrecht Centraal\r
+",
+            "",
+        );
+
+        let database_station_codes = HashSet::from(["rtd".to_string(), "ut".to_string()]);
+        let report = check_delivery(&delivery, Some(&database_station_codes));
+
+        let count = |category| report.issues.get(&category).map_or(0, BTreeSet::len);
+        assert_eq!(count(IssueCategory::NonMonotonicTimes), 1);
+        assert_eq!(count(IssueCategory::MissingPlatformInfo), 1);
+        assert_eq!(count(IssueCategory::AttributeOutOfRange), 1);
+        assert_eq!(count(IssueCategory::UnknownCompany), 1);
+        assert_eq!(count(IssueCategory::UnknownStation), 1);
+        assert_eq!(count(IssueCategory::UnknownStationInDatabase), 2);
+        assert_eq!(count(IssueCategory::FootnoteLengthMismatch), 1);
+    }
+
+    #[test]
+    fn it_compares_times_past_midnight_by_day() {
+        let delivery = test_delivery(
+            IDENTIFICATION,
+            "#00000001\r
+%100,01234,      ,001,002,                              \r
+-00000,000,999\r
+&IC  ,001,002\r
+>rtd    ,1000\r
+?1    ,1    ,00001\r
+<ut     ,2330\r
+?3    ,3    ,00001\r
+#00000002\r
+%100,05678,      ,001,002,                              \r
+-00000,000,999\r
+&IC  ,001,002\r
+>rtd    ,2200\r
+?1    ,1    ,00001\r
+<ut     ,0900\r
+?3    ,3    ,00001\r
+",
+            "",
+            "100,NS        ,NS                            ,0000\r\n",
+            "1,rtd    ,03,03,NL  ,0000,  ,000000,000000,Rotterdam Centraal\r
+1,ut     ,03,03,NL  ,0000,  ,000000,000000,Utrecht Centraal\r
+",
+            "",
+        );
+
+        let report = check_delivery(&delivery, None);
+
+        // a long leg is fine, but a time on the same day 13 hours earlier is not
+        let services = report.issues[&IssueCategory::NonMonotonicTimes]
+            .iter()
+            .map(|issue| issue.service)
+            .collect::<Vec<_>>();
+        assert_eq!(services, vec![Some(2)]);
+    }
+}
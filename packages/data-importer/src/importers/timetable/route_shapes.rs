@@ -0,0 +1,321 @@
+use crate::db;
+use crate::importers::timetable::Delivery;
+use crate::importers::timetable::distances::line_length;
+use crate::importers::timetable::parsers::service::ServiceLeg;
+use crate::instrumentation::{metrics, timed};
+use crate::logging::logger;
+use anyhow::{Context, Result};
+use deadpool_postgres::Pool;
+use sea_query::{Alias, Expr, OnConflict, PostgresQueryBuilder, Query};
+use sea_query_postgres::PostgresBinder;
+use serde::{Deserialize, Serialize};
+use slog::{info, warn};
+use std::collections::{BTreeSet, HashMap};
+use uuid::Uuid;
+
+/// Shapes are upserted in chunks, as a delivery has thousands of stop patterns.
+const CHUNK_SIZE: usize = 500;
+
+/// A stored route shape, with the track distance between consecutive stations for journeys to
+/// use.
+#[derive(Debug)]
+pub(crate) struct StoredRouteShape {
+    pub id: Uuid,
+    /// Metres from the previous station, so always `None` for the first one
+    pub distances: Vec<Option<f64>>,
+}
+
+/// Stored route shapes by stop pattern.
+pub(crate) type RouteShapes = HashMap<String, StoredRouteShape>;
+
+/// Track geometry between adjacent stations, by lowercase station codes.
+pub(crate) type Segments = HashMap<(String, String), Vec<(f64, f64)>>;
+
+#[derive(Debug, Deserialize)]
+struct LineString {
+    coordinates: Vec<(f64, f64)>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct MultiLineString {
+    r#type: &'static str,
+    coordinates: Vec<Vec<(f64, f64)>>,
+}
+
+/// Two adjacent stations without track geometry between them.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct Gap {
+    pub from: String,
+    pub to: String,
+}
+
+/// The track a stop pattern runs over. Every gap splits the shape in two, so the shape has one
+/// line more than it has gaps, except where a gap is at the very start or end.
+#[derive(Debug, PartialEq)]
+pub(crate) struct RouteShape {
+    pub lines: Vec<Vec<(f64, f64)>>,
+    pub gaps: Vec<Gap>,
+}
+
+/// Every station the leg passes, passages included, as that is what the track geometry is
+/// between.
+pub(crate) fn stations(leg: &ServiceLeg) -> Vec<&str> {
+    leg.station_events
+        .iter()
+        .map(|(event, _)| event.station.as_str())
+        .collect()
+}
+
+pub(crate) fn stop_pattern(leg: &ServiceLeg) -> String {
+    stations(leg).join(",")
+}
+
+/// Looks up the track between two adjacent stations, segments are stored in one direction only.
+pub(crate) fn segment(segments: &Segments, from: &str, to: &str) -> Option<Vec<(f64, f64)>> {
+    let (from, to) = (from.to_lowercase(), to.to_lowercase());
+    if let Some(line) = segments.get(&(from.clone(), to.clone())) {
+        return Some(line.clone());
+    }
+
+    segments.get(&(to, from)).map(|line| {
+        let mut line = line.clone();
+        line.reverse();
+        line
+    })
+}
+
+/// Track distance in metres between every station and the one before it.
+pub(crate) fn distances(stations: &[&str], segments: &Segments) -> Vec<Option<f64>> {
+    let mut distances = vec![None];
+    for pair in stations.windows(2) {
+        distances.push(match pair[0] == pair[1] {
+            true => Some(0.0),
+            false => segment(segments, pair[0], pair[1]).map(|line| line_length(&line)),
+        });
+    }
+
+    distances
+}
+
+/// Chains the segments between every pair of adjacent stations into one shape.
+pub(crate) fn chain(stations: &[&str], segments: &Segments) -> RouteShape {
+    let mut lines = Vec::new();
+    let mut gaps = Vec::new();
+    let mut line: Vec<(f64, f64)> = Vec::new();
+
+    for pair in stations.windows(2) {
+        // a train that stands still at a station is listed twice, there is no track to add
+        if pair[0] == pair[1] {
+            continue;
+        }
+
+        match segment(segments, pair[0], pair[1]) {
+            Some(segment) => {
+                // segments share their end points, which would otherwise be in the line twice
+                let skip = usize::from(line.last() == segment.first());
+                line.extend(segment.into_iter().skip(skip));
+            }
+            None => {
+                gaps.push(Gap {
+                    from: pair[0].to_string(),
+                    to: pair[1].to_string(),
+                });
+                if !line.is_empty() {
+                    lines.push(std::mem::take(&mut line));
+                }
+            }
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+
+    RouteShape { lines, gaps }
+}
+
+pub(crate) async fn load_segments(db: &Pool) -> Result<Segments> {
+    let client = db.get().await?;
+    let (sql, params) = Query::select()
+        .columns([db::StationGeometry::From, db::StationGeometry::To])
+        .expr(Expr::col(db::StationGeometry::LineString).cast_as(Alias::new("text")))
+        .from(db::StationGeometry::Table)
+        .build_postgres(PostgresQueryBuilder);
+
+    let rows = client
+        .query(sql.as_str(), &params.as_params())
+        .await
+        .context("! failed to load station geometry")?;
+
+    rows.iter()
+        .map(|row| {
+            let from: String = row.get(0);
+            let to: String = row.get(1);
+            let line_string: LineString = serde_json::from_str(row.get(2))
+                .with_context(|| format!("! invalid geometry between {from} and {to}"))?;
+
+            Ok((
+                (from.to_lowercase(), to.to_lowercase()),
+                line_string.coordinates,
+            ))
+        })
+        .collect()
+}
+
+/// Derives the shape of every stop pattern in the delivery from the station geometry, and stores
+/// them. Returns nothing if there is no station geometry yet.
+pub(crate) async fn store_route_shapes(db: &Pool, delivery: &Delivery) -> Result<RouteShapes> {
+    let segments = load_segments(db).await?;
+    if segments.is_empty() {
+        warn!(
+            logger(),
+            "No station geometry imported yet, journeys will not have a route shape"
+        );
+        return Ok(RouteShapes::new());
+    }
+
+    let legs = delivery
+        .timetable
+        .data
+        .iter()
+        .flat_map(|service| service.split_legs().unwrap_or_default());
+    let mut patterns = BTreeSet::new();
+    for leg in legs {
+        patterns.insert(
+            stations(&leg)
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    let client = db.get().await?;
+    let mut route_shapes = RouteShapes::new();
+    let mut with_gaps = 0;
+
+    let patterns = patterns.into_iter().collect::<Vec<_>>();
+    for chunk in patterns.chunks(CHUNK_SIZE) {
+        let mut insert = Query::insert();
+        insert
+            .into_table(db::RouteShape::Table)
+            .columns([
+                db::RouteShape::StopPattern,
+                db::RouteShape::Stations,
+                db::RouteShape::Shape,
+                db::RouteShape::Gaps,
+            ])
+            .on_conflict(
+                OnConflict::column(db::RouteShape::StopPattern)
+                    .update_columns([db::RouteShape::Shape, db::RouteShape::Gaps])
+                    .to_owned(),
+            )
+            .returning(
+                Query::returning().columns([db::RouteShape::Id, db::RouteShape::StopPattern]),
+            );
+
+        let mut chunk_distances = HashMap::new();
+        for stations in chunk {
+            let station_codes = stations.iter().map(String::as_str).collect::<Vec<_>>();
+            let shape = chain(&station_codes, &segments);
+            chunk_distances.insert(stations.join(","), distances(&station_codes, &segments));
+            if !shape.gaps.is_empty() {
+                with_gaps += 1;
+            }
+
+            insert.values_panic([
+                stations.join(",").into(),
+                stations.clone().into(),
+                Expr::val(serde_json::to_string(&MultiLineString {
+                    r#type: "MultiLineString",
+                    coordinates: shape.lines,
+                })?)
+                .cast_as(Alias::new("jsonb")),
+                Expr::val(serde_json::to_string(&shape.gaps)?).cast_as(Alias::new("jsonb")),
+            ]);
+        }
+
+        let (sql, params) = insert.build_postgres(PostgresQueryBuilder);
+        let rows = timed(
+            "upsert_route_shapes",
+            client.query(sql.as_str(), &params.as_params()),
+        )
+        .await
+        .context("! failed to upsert route shapes")?;
+
+        for row in rows {
+            let stop_pattern: String = row.get("stop_pattern");
+            let distances = chunk_distances.remove(&stop_pattern).unwrap_or_default();
+            route_shapes.insert(
+                stop_pattern,
+                StoredRouteShape {
+                    id: row.get("id"),
+                    distances,
+                },
+            );
+        }
+    }
+
+    metrics().record_rows("route_shape", route_shapes.len() as u64);
+    info!(logger(), "Stored route shapes";
+        "stop_patterns" => route_shapes.len(),
+        "with_gaps" => with_gaps,
+    );
+
+    Ok(route_shapes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn segments() -> Segments {
+        Segments::from([
+            (
+                ("rtd".to_string(), "rtn".to_string()),
+                vec![(4.46, 51.92), (4.48, 51.93), (4.49, 51.94)],
+            ),
+            (
+                ("gd".to_string(), "rtn".to_string()),
+                vec![(4.70, 52.02), (4.60, 51.98), (4.49, 51.94)],
+            ),
+        ])
+    }
+
+    #[test]
+    fn it_chains_segments_in_both_directions() {
+        let shape = chain(&["RTD", "rtn", "gd"], &segments());
+
+        assert_eq!(
+            shape,
+            RouteShape {
+                lines: vec![vec![
+                    (4.46, 51.92),
+                    (4.48, 51.93),
+                    (4.49, 51.94),
+                    (4.60, 51.98),
+                    (4.70, 52.02),
+                ]],
+                gaps: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn it_flags_gaps() {
+        let shape = chain(&["rtd", "rtn", "rtn", "ut", "gd"], &segments());
+
+        assert_eq!(shape.lines.len(), 1);
+        assert_eq!(
+            shape.gaps,
+            vec![
+                Gap {
+                    from: "rtn".to_string(),
+                    to: "ut".to_string()
+                },
+                Gap {
+                    from: "ut".to_string(),
+                    to: "gd".to_string()
+                },
+            ]
+        );
+    }
+}
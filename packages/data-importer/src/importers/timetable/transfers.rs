@@ -0,0 +1,481 @@
+use crate::db;
+use crate::importers::timetable::Delivery;
+use crate::importers::timetable::parsers::change::{ChangePossibility, Changes};
+use crate::importers::timetable::parsers::service::ServiceLeg;
+use crate::importers::timetable::parsers::station::Stations;
+use crate::instrumentation::{metrics, timed};
+use crate::logging::logger;
+use anyhow::{Context, Result};
+use deadpool_postgres::Pool;
+use sea_query::{OnConflict, PostgresQueryBuilder, Query};
+use sea_query_postgres::PostgresBinder;
+use slog::{debug, info};
+use std::cmp::Reverse;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+
+/// Rows are inserted in chunks, as changes.dat has tens of thousands of service pairs.
+const CHUNK_SIZE: usize = 1000;
+
+/// Where a minimum transfer time comes from.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TransferSource {
+    /// A service pair in changes.dat
+    Service,
+    /// The minimum of the station in stations.dat
+    Station,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Transfer {
+    pub minutes: u8,
+    /// The connecting service waits for the arriving one
+    pub guaranteed: bool,
+    pub source: TransferSource,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct StationDefault {
+    is_interchange: bool,
+    minimum_minutes: u8,
+}
+
+/// Minimum transfer times between services, by station code and IFF service ids.
+#[derive(Debug, Default)]
+pub struct TransferModel {
+    stations: HashMap<String, StationDefault>,
+    overrides: HashMap<(String, u32, u32), ChangePossibility>,
+}
+
+impl TransferModel {
+    pub fn new(stations: &Stations, changes: &Changes) -> Self {
+        TransferModel {
+            stations: stations
+                .data
+                .iter()
+                .map(|station| {
+                    (
+                        station.code.to_lowercase(),
+                        StationDefault {
+                            is_interchange: station.is_interchange,
+                            minimum_minutes: station.layover_minimum_minutes,
+                        },
+                    )
+                })
+                .collect(),
+            overrides: changes
+                .data
+                .iter()
+                .map(|change| {
+                    (
+                        (
+                            change.station.to_lowercase(),
+                            change.from_service,
+                            change.to_service,
+                        ),
+                        change.possibility,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    pub fn from_delivery(delivery: &Delivery) -> Self {
+        TransferModel::new(&delivery.stations, &delivery.changes)
+    }
+
+    /// The minimum time to change from `from_service` to `to_service` at `station`, or `None` if
+    /// passengers cannot change between them there. A service pair in changes.dat wins over the
+    /// station: the planners checked that pair, so the station minimum does not apply to it.
+    /// Otherwise changing is only possible at interchange stations, in their minimum time.
+    pub fn minimum_transfer_time(
+        &self,
+        station: &str,
+        from_service: u32,
+        to_service: u32,
+    ) -> Option<Transfer> {
+        let station = station.to_lowercase();
+
+        if let Some(possibility) = self
+            .overrides
+            .get(&(station.clone(), from_service, to_service))
+        {
+            return match possibility {
+                ChangePossibility::NotPossible => None,
+                ChangePossibility::Possible | ChangePossibility::Guaranteed => Some(Transfer {
+                    minutes: 0,
+                    guaranteed: *possibility == ChangePossibility::Guaranteed,
+                    source: TransferSource::Service,
+                }),
+            };
+        }
+
+        let default = self.stations.get(&station)?;
+        default.is_interchange.then_some(Transfer {
+            minutes: default.minimum_minutes,
+            guaranteed: false,
+            source: TransferSource::Station,
+        })
+    }
+}
+
+fn possibility_name(possibility: ChangePossibility) -> &'static str {
+    match possibility {
+        ChangePossibility::NotPossible => "not_possible",
+        ChangePossibility::Possible => "possible",
+        ChangePossibility::Guaranteed => "guaranteed",
+    }
+}
+
+/// Train numbers of the legs arriving at and departing from `station`, which is where a service
+/// with two numbers changes from one to the other.
+fn train_numbers_at(legs: &[ServiceLeg], station: &str) -> (Option<String>, Option<String>) {
+    let calls_at = |leg: &&ServiceLeg, arriving: bool| {
+        leg.station_events.iter().any(|(event, _)| {
+            event.station.eq_ignore_ascii_case(station)
+                && match arriving {
+                    true => event.arrival_time.is_some(),
+                    false => event.departure_time.is_some(),
+                }
+        })
+    };
+
+    (
+        legs.iter()
+            .rev()
+            .find(|leg| calls_at(leg, true))
+            .and_then(ServiceLeg::train_number),
+        legs.iter()
+            .find(|leg| calls_at(leg, false))
+            .and_then(ServiceLeg::train_number),
+    )
+}
+
+/// A service pair by the train numbers the API knows services by.
+#[derive(Debug, PartialEq)]
+struct ServiceTransfer {
+    possibility: ChangePossibility,
+    minutes: Option<u8>,
+}
+
+impl ServiceTransfer {
+    /// Whether this promises passengers less than `other`: a transfer that is not possible over
+    /// one that is, one that is possible over a guaranteed one, or a longer transfer time.
+    fn is_more_restrictive_than(&self, other: &ServiceTransfer) -> bool {
+        let promise = |transfer: &ServiceTransfer| {
+            let possibility = match transfer.possibility {
+                ChangePossibility::NotPossible => 0,
+                ChangePossibility::Possible => 1,
+                ChangePossibility::Guaranteed => 2,
+            };
+            (possibility, Reverse(transfer.minutes))
+        };
+
+        promise(self) < promise(other)
+    }
+}
+
+fn service_transfers(
+    delivery: &Delivery,
+    model: &TransferModel,
+) -> BTreeMap<(String, String, String), ServiceTransfer> {
+    let legs = delivery
+        .timetable
+        .data
+        .iter()
+        .map(|service| {
+            (
+                service.identification.0,
+                service.split_legs().unwrap_or_default(),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut transfers = BTreeMap::new();
+    for change in &delivery.changes.data {
+        let (Some(from), Some(to)) = (legs.get(&change.from_service), legs.get(&change.to_service))
+        else {
+            continue;
+        };
+        let (Some(from), _) = train_numbers_at(from, &change.station) else {
+            continue;
+        };
+        let (_, Some(to)) = train_numbers_at(to, &change.station) else {
+            continue;
+        };
+
+        let transfer = ServiceTransfer {
+            possibility: change.possibility,
+            minutes: model
+                .minimum_transfer_time(&change.station, change.from_service, change.to_service)
+                .map(|transfer| transfer.minutes),
+        };
+
+        // services that only differ in the days they run on share their train numbers, so when
+        // their transfers differ, only the most restrictive one holds on every day
+        match transfers.entry((change.station.to_lowercase(), from, to)) {
+            Entry::Vacant(entry) => {
+                entry.insert(transfer);
+            }
+            Entry::Occupied(mut entry) if *entry.get() != transfer => {
+                let ((station, from, to), stored) = (entry.key(), entry.get());
+                debug!(logger(), "Conflicting transfers between day variants";
+                    "station" => station,
+                    "from" => from,
+                    "to" => to,
+                    "stored" => format!("{stored:?}"),
+                    "conflicting" => format!("{transfer:?}"),
+                );
+                if transfer.is_more_restrictive_than(stored) {
+                    entry.insert(transfer);
+                }
+            }
+            Entry::Occupied(_) => {}
+        }
+    }
+
+    transfers
+}
+
+/// Replaces the stored transfer times with those of the delivery, for the API to look up by
+/// station code and train numbers.
+pub(crate) async fn store_transfers(db: &Pool, delivery: &Delivery) -> Result<()> {
+    let model = TransferModel::from_delivery(delivery);
+    let transfers = service_transfers(delivery, &model);
+
+    let mut client = db.get().await?;
+    let transaction = client
+        .transaction()
+        .await
+        .context("! failed to start transaction")?;
+
+    for chunk in delivery.stations.data.chunks(CHUNK_SIZE) {
+        let mut insert = Query::insert();
+        insert
+            .into_table(db::StationTransfer::Table)
+            .columns([
+                db::StationTransfer::StationCode,
+                db::StationTransfer::IsInterchange,
+                db::StationTransfer::MinimumTransferMinutes,
+            ])
+            .on_conflict(
+                OnConflict::column(db::StationTransfer::StationCode)
+                    .update_columns([
+                        db::StationTransfer::IsInterchange,
+                        db::StationTransfer::MinimumTransferMinutes,
+                    ])
+                    .to_owned(),
+            );
+        for station in chunk {
+            insert.values_panic([
+                station.code.to_lowercase().into(),
+                station.is_interchange.into(),
+                i32::from(station.layover_minimum_minutes).into(),
+            ]);
+        }
+
+        let (sql, params) = insert.build_postgres(PostgresQueryBuilder);
+        timed(
+            "upsert_station_transfers",
+            transaction.execute(sql.as_str(), &params.as_params()),
+        )
+        .await
+        .context("! failed to upsert station transfers")?;
+    }
+
+    // service pairs of earlier deliveries may no longer apply
+    let (sql, params) = Query::delete()
+        .from_table(db::ServiceTransfer::Table)
+        .build_postgres(PostgresQueryBuilder);
+    transaction
+        .execute(sql.as_str(), &params.as_params())
+        .await
+        .context("! failed to delete service transfers")?;
+
+    let transfers = transfers.into_iter().collect::<Vec<_>>();
+    for chunk in transfers.chunks(CHUNK_SIZE) {
+        let mut insert = Query::insert();
+        insert.into_table(db::ServiceTransfer::Table).columns([
+            db::ServiceTransfer::StationCode,
+            db::ServiceTransfer::FromTrainNumber,
+            db::ServiceTransfer::ToTrainNumber,
+            db::ServiceTransfer::Possibility,
+            db::ServiceTransfer::MinimumTransferMinutes,
+        ]);
+        for ((station, from, to), transfer) in chunk {
+            insert.values_panic([
+                station.clone().into(),
+                from.clone().into(),
+                to.clone().into(),
+                possibility_name(transfer.possibility).into(),
+                transfer.minutes.map(i32::from).into(),
+            ]);
+        }
+
+        let (sql, params) = insert.build_postgres(PostgresQueryBuilder);
+        timed(
+            "insert_service_transfers",
+            transaction.execute(sql.as_str(), &params.as_params()),
+        )
+        .await
+        .context("! failed to insert service transfers")?;
+    }
+
+    transaction
+        .commit()
+        .await
+        .context("! failed to commit transfers")?;
+
+    metrics().record_rows("station_transfer", delivery.stations.data.len() as u64);
+    metrics().record_rows("service_transfer", transfers.len() as u64);
+    info!(logger(), "Stored transfer times";
+        "stations" => delivery.stations.data.len(),
+        "service_pairs" => transfers.len(),
+        "skipped_service_pairs" => delivery.changes.data.len() - transfers.len(),
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::importers::timetable::test_support::{IDENTIFICATION, test_delivery};
+
+    fn delivery() -> Delivery {
+        test_delivery(
+            IDENTIFICATION,
+            "#00000001\r
+%100,01234,      ,001,002,                              \r
+-00000,000,999\r
+&SPR ,001,002\r
+>rtd    ,1000\r
+<gd     ,1020\r
+#00000002\r
+%100,02345,      ,001,002,                              \r
+%100,02346,      ,002,003,                              \r
+-00000,000,999\r
+&IC  ,001,003\r
+>rtd    ,1005\r
++gd     ,1022,1024\r
+<ut     ,1040\r
+#00000003\r
+%100,03456,      ,001,002,                              \r
+-00000,000,999\r
+&SPR ,001,002\r
+>gd     ,1030\r
+<ut     ,1050\r
+",
+            "",
+            "",
+            "1,rtd    ,03,03,NL  ,0000,  ,000000,000000,Rotterdam Centraal\r
+1,gd     ,02,02,NL  ,0000,  ,000000,000000,Gouda\r
+0,ut     ,03,03,NL  ,0000,  ,000000,000000,Utrecht Centraal\r
+",
+            "#gd\r
+-00000001,00000002,2\r
+-00000002,00000003,0\r
+",
+        )
+    }
+
+    #[test]
+    fn it_prefers_service_pairs_over_station_defaults() {
+        let model = TransferModel::from_delivery(&delivery());
+
+        assert_eq!(
+            model.minimum_transfer_time("GD", 1, 2),
+            Some(Transfer {
+                minutes: 0,
+                guaranteed: true,
+                source: TransferSource::Service,
+            })
+        );
+        assert_eq!(model.minimum_transfer_time("gd", 2, 3), None);
+        assert_eq!(
+            model.minimum_transfer_time("gd", 1, 3),
+            Some(Transfer {
+                minutes: 2,
+                guaranteed: false,
+                source: TransferSource::Station,
+            })
+        );
+        // not an interchange station
+        assert_eq!(model.minimum_transfer_time("ut", 2, 3), None);
+        assert_eq!(model.minimum_transfer_time("asd", 2, 3), None);
+    }
+
+    #[test]
+    fn it_resolves_train_numbers_of_service_pairs() {
+        let delivery = delivery();
+        let model = TransferModel::from_delivery(&delivery);
+
+        assert_eq!(
+            service_transfers(&delivery, &model),
+            BTreeMap::from([
+                (
+                    ("gd".to_string(), "1234".to_string(), "2346".to_string()),
+                    ServiceTransfer {
+                        possibility: ChangePossibility::Guaranteed,
+                        minutes: Some(0),
+                    }
+                ),
+                (
+                    ("gd".to_string(), "2345".to_string(), "3456".to_string()),
+                    ServiceTransfer {
+                        possibility: ChangePossibility::NotPossible,
+                        minutes: None,
+                    }
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn it_keeps_the_most_restrictive_transfer_of_day_variants() {
+        let delivery = test_delivery(
+            IDENTIFICATION,
+            "#00000001\r
+%100,01234,      ,001,002,                              \r
+-00000,000,999\r
+&SPR ,001,002\r
+>rtd    ,1000\r
+<gd     ,1020\r
+#00000002\r
+%100,02345,      ,001,002,                              \r
+-00000,000,999\r
+&IC  ,001,002\r
+>gd     ,1022\r
+<ut     ,1040\r
+#00000003\r
+%100,01234,      ,001,002,                              \r
+-00000,000,999\r
+&SPR ,001,002\r
+>rtd    ,1000\r
+<gd     ,1020\r
+",
+            "",
+            "",
+            "1,rtd    ,03,03,NL  ,0000,  ,000000,000000,Rotterdam Centraal\r
+1,gd     ,02,02,NL  ,0000,  ,000000,000000,Gouda\r
+1,ut     ,03,03,NL  ,0000,  ,000000,000000,Utrecht Centraal\r
+",
+            "#gd\r
+-00000001,00000002,2\r
+-00000003,00000002,0\r
+",
+        );
+        let model = TransferModel::from_delivery(&delivery);
+
+        assert_eq!(
+            service_transfers(&delivery, &model),
+            BTreeMap::from([(
+                ("gd".to_string(), "1234".to_string(), "2345".to_string()),
+                ServiceTransfer {
+                    possibility: ChangePossibility::NotPossible,
+                    minutes: None,
+                }
+            )])
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use crate::importers::timetable::load_delivery;
+use crate::importers::timetable::parsers::company::Companies;
+use crate::importers::timetable::parsers::footnote::{Footnote, Footnotes};
+use crate::importers::timetable::parsers::station::Stations;
+use crate::importers::timetable::parsers::timetable::Timetable;
+use anyhow::{Result, bail};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ValidationReport {
+    pub version_number: String,
+    pub first_valid: String,
+    pub last_valid: String,
+
+    pub services: usize,
+    pub legs: usize,
+    pub journeys: usize,
+    pub footnotes: usize,
+    pub companies: usize,
+    pub stations: usize,
+
+    /// Services that could not be split into legs, with the reason why
+    pub unsplittable_services: BTreeMap<u32, String>,
+    /// Footnote ids referenced by services but not present in footnote.dat
+    pub missing_footnotes: BTreeSet<u32>,
+    /// Company numbers referenced by services but not present in company.dat
+    pub unknown_companies: BTreeSet<u32>,
+    /// Station codes referenced by services but not present in stations.dat
+    pub unknown_stations: BTreeSet<String>,
+}
+
+impl ValidationReport {
+    pub fn has_problems(&self) -> bool {
+        !self.unsplittable_services.is_empty()
+            || !self.missing_footnotes.is_empty()
+            || !self.unknown_companies.is_empty()
+            || !self.unknown_stations.is_empty()
+    }
+}
+
+pub fn validate_delivery(
+    timetable: &Timetable,
+    footnotes: &Footnotes,
+    companies: &Companies,
+    stations: &Stations,
+) -> ValidationReport {
+    let mut report = ValidationReport {
+        version_number: timetable.identification.version_number.clone(),
+        first_valid: timetable.identification.first_valid.to_string(),
+        last_valid: timetable.identification.last_valid.to_string(),
+        services: timetable.data.len(),
+        footnotes: footnotes.data.len(),
+        companies: companies.data.len(),
+        stations: stations.data.len(),
+        ..Default::default()
+    };
+
+    let known_stations = stations
+        .data
+        .iter()
+        .map(|station| station.code.as_str())
+        .collect::<BTreeSet<_>>();
+
+    let always_valid = Footnote::always_valid(&timetable.identification);
+
+    for service in &timetable.data {
+        for (event, _) in &service.station_events {
+            if !known_stations.contains(event.station.as_str()) {
+                report.unknown_stations.insert(event.station.clone());
+            }
+        }
+
+        let legs = match service.split_legs() {
+            Ok(legs) => legs,
+            Err(e) => {
+                report
+                    .unsplittable_services
+                    .insert(service.identification.0, e.to_string());
+                continue;
+            }
+        };
+
+        let footnote = if service.validity.footnote == 0 {
+            Some(&always_valid)
+        } else {
+            footnotes.get_by_id(service.validity.footnote)
+        };
+
+        if footnote.is_none() {
+            report.missing_footnotes.insert(service.validity.footnote);
+        }
+
+        let running_days = footnote
+            .map(|footnote| {
+                footnote
+                    .iterate_valid_dates(&timetable.identification)
+                    .flatten()
+                    .count()
+            })
+            .unwrap_or_default();
+
+        for leg in legs {
+            report.legs += 1;
+            report.journeys += running_days;
+
+            if companies
+                .get_by_id(leg.service_number.company_number)
+                .is_none()
+            {
+                report
+                    .unknown_companies
+                    .insert(leg.service_number.company_number);
+            }
+        }
+    }
+
+    report
+}
+
+/// Parses the delivery in `input_path` and prints the validation report as JSON, without touching
+/// the database. Fails if the delivery can't be parsed or any problems were found.
+pub fn validate(input_path: String) -> Result<()> {
+    let delivery = load_delivery(&PathBuf::from(input_path))?;
+    let report = validate_delivery(
+        &delivery.timetable,
+        &delivery.footnotes,
+        &delivery.companies,
+        &delivery.stations,
+    );
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    if report.has_problems() {
+        bail!("! delivery has problems, see report");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::importers::timetable::parsers::{
+        company::company_file, footnote::footnote_file, station::station_file,
+        timetable::timetable_file,
+    };
+
+    const IDENTIFICATION: &str = "@100,01062025,05062025,0001,IFF Standaard uit RIF\r\n";
+
+    #[test]
+    fn it_validates_delivery() {
+        let timetable = format!(
+            "{IDENTIFICATION}#00000001\r
+%100,01234,      ,001,002,                              \r
+-00001,000,999\r
+&SPR ,001,002\r
+>rtd    ,1000\r
+?1    ,1    ,00001\r
+<gd     ,1020\r
+?2    ,2    ,00001\r
+#00000002\r
+%999,05678,      ,001,002,                              \r
+-00002,000,999\r
+&IC  ,001,002\r
+>rtd    ,1100\r
+;xyz\r
+<asd    ,1200\r
+?3    ,3    ,00001\r
+#00000003\r
+%100,00001,      ,001,002,                              \r
+%100,00002,      ,002,003,                              \r
+-00000,000,999\r
+&IC  ,001,003\r
+>rtd    ,1300\r
+?1    ,1    ,00001\r
+.gd     ,1320\r
+?2    ,2    ,00001\r
+<ut     ,1350\r
+?3    ,3    ,00001\r
+"
+        );
+        let footnotes = format!("{IDENTIFICATION}#00001\r\n11001\r\n");
+        let companies =
+            format!("{IDENTIFICATION}100,NS        ,NS                            ,0000\r\n");
+        let stations = format!(
+            "{IDENTIFICATION}1,rtd    ,03,03,NL  ,0000,  ,000000,000000,Rotterdam Centraal\r
+1,gd     ,02,02,NL  ,0000,  ,000000,000000,Gouda\r
+1,ut     ,03,03,NL  ,0000,  ,000000,000000,Utrecht Centraal\r
+1,asd    ,03,03,NL  ,0000,  ,000000,000000,Amsterdam Centraal\r
+"
+        );
+
+        let (_, timetable) = timetable_file(&timetable).expect("failed to parse timetable");
+        let (_, footnotes) = footnote_file(&footnotes).expect("failed to parse footnotes");
+        let (_, companies) = company_file(&companies).expect("failed to parse companies");
+        let (_, stations) = station_file(&stations).expect("failed to parse stations");
+
+        let report = validate_delivery(&timetable, &footnotes, &companies, &stations);
+
+        assert_eq!(report.services, 3);
+        assert_eq!(report.legs, 4);
+        // service 1 runs on three days, 2 has no footnote, both legs of 3 run on all five days
+        assert_eq!(report.journeys, 3 + 5 + 5);
+        assert_eq!(report.missing_footnotes, BTreeSet::from([2]));
+        assert_eq!(report.unknown_companies, BTreeSet::from([999]));
+        assert_eq!(report.unknown_stations, BTreeSet::from(["xyz".to_string()]));
+        assert!(report.unsplittable_services.is_empty());
+        assert!(report.has_problems());
+    }
+}
@@ -0,0 +1,194 @@
+use crate::logging::logger;
+use anyhow::Result;
+use opentelemetry::global::BoxedTracer;
+use opentelemetry::metrics::{Counter, Histogram, Meter};
+use opentelemetry::trace::{FutureExt, Status, TraceContextExt, Tracer};
+use opentelemetry::{Context, InstrumentationScope, KeyValue, global};
+use opentelemetry_otlp::{MetricExporter, SpanExporter};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::metrics::periodic_reader_with_async_runtime::PeriodicReader;
+use opentelemetry_sdk::runtime::Tokio;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::trace::span_processor_with_async_runtime::BatchSpanProcessor;
+use slog::{error, info};
+use std::env;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+const SERVICE_NAME: &str = "kedeng/data-importer";
+
+/// Telemetry is only exported when a collector is configured, so running the importer locally
+/// doesn't fail or stall on flushing to a collector that isn't there.
+fn is_enabled() -> bool {
+    env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_some()
+}
+
+fn get_resource() -> Resource {
+    Resource::builder().with_service_name(SERVICE_NAME).build()
+}
+
+fn get_tracer_provider() -> &'static SdkTracerProvider {
+    static TRACER_PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+    TRACER_PROVIDER.get_or_init(|| {
+        SdkTracerProvider::builder()
+            .with_span_processor(
+                BatchSpanProcessor::builder(
+                    SpanExporter::builder().with_tonic().build().unwrap(),
+                    Tokio,
+                )
+                .build(),
+            )
+            .with_resource(get_resource())
+            .build()
+    })
+}
+
+fn get_metrics_provider() -> &'static SdkMeterProvider {
+    static METRICS_PROVIDER: OnceLock<SdkMeterProvider> = OnceLock::new();
+    METRICS_PROVIDER.get_or_init(|| {
+        SdkMeterProvider::builder()
+            .with_reader(
+                PeriodicReader::builder(
+                    MetricExporter::builder().with_tonic().build().unwrap(),
+                    Tokio,
+                )
+                .build(),
+            )
+            .with_resource(get_resource())
+            .build()
+    })
+}
+
+/// Installs the OTLP exporters as global providers. Has to be called before anything is recorded,
+/// instruments created before that stay no-ops.
+pub fn init_telemetry() {
+    if !is_enabled() {
+        info!(
+            logger(),
+            "OTEL_EXPORTER_OTLP_ENDPOINT is not set, not exporting telemetry"
+        );
+        return;
+    }
+
+    global::set_tracer_provider(get_tracer_provider().clone());
+    global::set_meter_provider(get_metrics_provider().clone());
+}
+
+/// Flushes everything that is still buffered, failing to do so should not fail the import.
+pub fn shutdown_telemetry() {
+    if !is_enabled() {
+        return;
+    }
+
+    if let Err(e) = get_tracer_provider().shutdown() {
+        error!(logger(), "Failed to flush spans"; "error" => e.to_string());
+    }
+    if let Err(e) = get_metrics_provider().shutdown() {
+        error!(logger(), "Failed to flush metrics"; "error" => e.to_string());
+    }
+}
+
+fn get_instrumentation_scope() -> &'static InstrumentationScope {
+    static SCOPE: OnceLock<InstrumentationScope> = OnceLock::new();
+    SCOPE.get_or_init(|| {
+        InstrumentationScope::builder(SERVICE_NAME)
+            .with_attributes([KeyValue::new("service.name", SERVICE_NAME)])
+            .build()
+    })
+}
+
+fn get_meter() -> &'static Meter {
+    static METER: OnceLock<Meter> = OnceLock::new();
+    METER.get_or_init(|| global::meter_with_scope(get_instrumentation_scope().clone()))
+}
+
+fn get_tracer() -> &'static BoxedTracer {
+    static TRACER: OnceLock<BoxedTracer> = OnceLock::new();
+    TRACER.get_or_init(|| global::tracer_with_scope(get_instrumentation_scope().clone()))
+}
+
+pub(crate) struct ImportMetrics {
+    /// Services (or journeys from GTFS and NeTEx) handled, by `result`: processed, skipped or failed
+    pub services: Counter<u64>,
+    /// Rows inserted or updated, by `table`
+    pub rows_written: Counter<u64>,
+    /// Duration of database queries in seconds, by `query`
+    pub db_latency: Histogram<f64>,
+}
+
+pub(crate) fn metrics() -> &'static ImportMetrics {
+    static METRICS: OnceLock<ImportMetrics> = OnceLock::new();
+    METRICS.get_or_init(|| ImportMetrics {
+        services: get_meter().u64_counter("kedeng_import_services").build(),
+        rows_written: get_meter()
+            .u64_counter("kedeng_import_rows_written")
+            .build(),
+        db_latency: get_meter()
+            .f64_histogram("kedeng_import_db_latency")
+            .with_unit("s")
+            .build(),
+    })
+}
+
+impl ImportMetrics {
+    pub fn record_service(&self, result: &'static str) {
+        self.services.add(1, &[KeyValue::new("result", result)]);
+    }
+
+    pub fn record_rows(&self, table: &'static str, rows: u64) {
+        self.rows_written
+            .add(rows, &[KeyValue::new("table", table)]);
+    }
+}
+
+/// Awaits a database query and records how long it took.
+pub(crate) async fn timed<T>(query: &'static str, future: impl Future<Output = T>) -> T {
+    let start = Instant::now();
+    let output = future.await;
+    metrics().db_latency.record(
+        start.elapsed().as_secs_f64(),
+        &[KeyValue::new("query", query)],
+    );
+
+    output
+}
+
+/// Runs `future` in a span that is a child of the current one, marking the span as failed if the
+/// future returns an error.
+pub(crate) async fn in_span<T>(
+    name: &'static str,
+    attributes: Vec<KeyValue>,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let span = get_tracer()
+        .span_builder(name)
+        .with_attributes(attributes)
+        .start(get_tracer());
+    let cx = Context::current_with_span(span);
+
+    let output = future.with_context(cx.clone()).await;
+    if let Err(e) = &output {
+        cx.span().set_status(Status::error(format!("{e:#}")));
+    }
+    cx.span().end();
+
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[tokio::test]
+    async fn it_passes_results_through_spans() {
+        let ok = in_span("ok", vec![], async { Ok(42) }).await;
+        assert_eq!(ok.unwrap(), 42);
+
+        let failed = in_span("failed", vec![], async { Err::<(), _>(anyhow!("! nope")) }).await;
+        assert_eq!(failed.unwrap_err().to_string(), "! nope");
+
+        assert_eq!(timed("query", async { 1 }).await, 1);
+    }
+}
@@ -1,4 +1,10 @@
-mod db;
+pub mod db;
+pub mod exporters;
 pub mod importers;
-pub(crate) mod ns;
+pub mod instrumentation;
+pub mod logging;
+pub mod ns;
+pub mod progress;
+pub mod schema;
 pub(crate) mod util;
+pub mod watch;
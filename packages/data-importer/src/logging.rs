@@ -0,0 +1,33 @@
+use slog::{Drain, Level, Logger, o};
+use slog_term::{FullFormat, TermDecorator};
+use std::io::IsTerminal;
+use std::sync::{Mutex, OnceLock};
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Logs JSON when not attached to a terminal, like the receiver does. Unlike the receiver, the
+/// drains are synchronous: the importer usually exits right after logging its summary, and an
+/// async drain could drop the last records on the way out. Logs go to stderr, as stdout is used
+/// for the reports of commands like `validate` and `diff`.
+fn build_logger(level: Level) -> Logger {
+    if !std::io::stderr().is_terminal() {
+        let drain = Mutex::new(slog_json::Json::default(std::io::stderr())).map(slog::Fuse);
+        return Logger::root(drain.filter_level(level).fuse(), o!());
+    }
+
+    let decorator = TermDecorator::new().stderr().build();
+    let drain = Mutex::new(FullFormat::new(decorator).build()).map(slog::Fuse);
+
+    Logger::root(drain.filter_level(level).fuse(), o!())
+}
+
+/// Sets up the logger, `verbose` includes debug records like the ones for every single service.
+/// Has no effect if something was logged before.
+pub fn init_logger(verbose: bool) {
+    let level = if verbose { Level::Debug } else { Level::Info };
+    let _ = LOGGER.set(build_logger(level));
+}
+
+pub fn logger() -> &'static Logger {
+    LOGGER.get_or_init(|| build_logger(Level::Info))
+}
@@ -1,13 +1,345 @@
-use anyhow::Result;
-use reqwest::ClientBuilder;
-use reqwest::header::{HeaderMap, HeaderValue};
+use crate::logging::logger;
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+use reqwest::{ClientBuilder, Response, StatusCode};
+use slog::{info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
 
-pub(crate) fn create_ns_api_client(api_key: &str) -> Result<reqwest::Client> {
+/// Timeouts, retries and limits for requests to the NS API.
+#[derive(Debug, Clone)]
+pub struct NsApiConfig {
+    /// Retries after the first attempt, for rate limiting, server errors and timeouts
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every retry after that
+    pub initial_backoff: Duration,
+    /// Upper bound for both the backoff and a `Retry-After` given by the API
+    pub max_backoff: Duration,
+    /// Timeout for a single request, including reading the body
+    pub timeout: Duration,
+    /// Maximum number of requests in a single run, including retries
+    pub request_budget: Option<u32>,
+}
+
+impl Default for NsApiConfig {
+    fn default() -> Self {
+        NsApiConfig {
+            max_retries: 4,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+            timeout: Duration::from_secs(30),
+            request_budget: None,
+        }
+    }
+}
+
+pub(crate) fn create_ns_api_client(api_key: &str, config: &NsApiConfig) -> Result<reqwest::Client> {
     let mut api_key_header = HeaderValue::from_str(api_key)?;
     api_key_header.set_sensitive(true);
 
     let mut headers = HeaderMap::new();
     headers.insert("Ocp-Apim-Subscription-Key", api_key_header);
 
-    Ok(ClientBuilder::new().default_headers(headers).user_agent("kedeng/0.1").build()?)
+    Ok(ClientBuilder::new()
+        .default_headers(headers)
+        .user_agent("kedeng/0.1")
+        .timeout(config.timeout)
+        .build()?)
+}
+
+/// Client for the NS API portal that retries rate limited and failed requests with exponential
+/// backoff, honouring `Retry-After`, and stops once its request budget is used up.
+#[derive(Debug, Clone)]
+pub struct NsApiClient {
+    client: reqwest::Client,
+    config: NsApiConfig,
+    requests_made: Arc<AtomicU32>,
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+impl NsApiClient {
+    pub fn new(api_key: &str, config: NsApiConfig) -> Result<Self> {
+        Ok(NsApiClient {
+            client: create_ns_api_client(api_key, &config)?,
+            config,
+            requests_made: Arc::new(AtomicU32::new(0)),
+        })
+    }
+
+    /// A client sharing the connections of this one, with a request budget of its own. The
+    /// watcher uses a new one for every check, so the budget applies per import.
+    pub fn for_run(&self) -> Self {
+        NsApiClient {
+            client: self.client.clone(),
+            config: self.config.clone(),
+            requests_made: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        self.config
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.config.max_backoff)
+    }
+
+    fn take_from_budget(&self) -> Result<()> {
+        let requests_made = self.requests_made.fetch_add(1, Ordering::SeqCst);
+        match self.config.request_budget {
+            Some(budget) if requests_made >= budget => {
+                bail!("! NS API request budget of {budget} requests exhausted")
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Gets the body of `url` as text, retrying when the API is rate limiting or failing.
+    pub async fn get_text(&self, url: &str) -> Result<String> {
+        let mut retry = 0;
+
+        loop {
+            self.take_from_budget()?;
+
+            let delay = match self.client.get(url).send().await {
+                Ok(response) if response.status().is_success() => match response.text().await {
+                    Ok(body) => return Ok(body),
+                    Err(e) if e.is_timeout() && retry < self.config.max_retries => {
+                        warn!(logger(), "Timed out reading response, retrying"; "url" => url);
+                        self.backoff(retry)
+                    }
+                    Err(e) => return Err(e).context("! failed to read NS API response"),
+                },
+                Ok(response)
+                    if is_retryable(response.status()) && retry < self.config.max_retries =>
+                {
+                    warn!(logger(), "NS API request failed, retrying"; "status" => response.status().as_u16());
+                    retry_after(&response)
+                        .map(|delay| delay.min(self.config.max_backoff))
+                        .unwrap_or_else(|| self.backoff(retry))
+                }
+                Ok(response) => {
+                    bail!("! NS API responded with {} for {url}", response.status())
+                }
+                Err(e) if (e.is_timeout() || e.is_connect()) && retry < self.config.max_retries => {
+                    warn!(logger(), "Request to NS API failed, retrying"; "url" => url, "error" => e.to_string());
+                    self.backoff(retry)
+                }
+                Err(e) => return Err(e).context("! request to NS API failed"),
+            };
+
+            tokio::time::sleep(delay).await;
+            retry += 1;
+        }
+    }
+}
+
+/// Where an NS API response comes from: the API itself, or a response that was saved before.
+#[derive(Debug, Clone)]
+pub enum ResponseSource {
+    Api(NsApiClient),
+    File(PathBuf),
+}
+
+impl ResponseSource {
+    /// The same source, with a fresh request budget when it is the API.
+    pub fn for_run(&self) -> Self {
+        match self {
+            ResponseSource::Api(client) => ResponseSource::Api(client.for_run()),
+            ResponseSource::File(path) => ResponseSource::File(path.clone()),
+        }
+    }
+}
+
+/// Gets the raw response body for `url` from `source`, and saves it to `dump` if given so it can
+/// be used as an input file later on.
+pub(crate) async fn load_response(
+    url: &str,
+    source: &ResponseSource,
+    dump: Option<&Path>,
+) -> Result<String> {
+    let body = match source {
+        ResponseSource::Api(client) => client.get_text(url).await?,
+        ResponseSource::File(path) => {
+            info!(logger(), "Reading response from file"; "path" => path.display().to_string());
+            fs::read_to_string(path).context("! failed to read input file")?
+        }
+    };
+
+    if let Some(dump) = dump {
+        fs::write(dump, &body).context("! failed to write dump")?;
+        info!(logger(), "Saved response"; "path" => dump.display().to_string());
+    }
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::time::Instant;
+    use uuid::Uuid;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn test_client(config: NsApiConfig) -> NsApiClient {
+        NsApiClient::new(
+            "secret",
+            NsApiConfig {
+                initial_backoff: Duration::from_millis(10),
+                ..config
+            },
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_loads_and_dumps_responses_from_file() {
+        let dump = env::temp_dir().join(format!("kedeng-dump-{}.json", Uuid::new_v4()));
+        let source = ResponseSource::File(PathBuf::from("./src/importers/stations/missing.json"));
+
+        let body = load_response("http://localhost", &source, Some(&dump))
+            .await
+            .unwrap();
+
+        assert!(body.contains("\"payload\""));
+        assert_eq!(fs::read_to_string(&dump).unwrap(), body);
+
+        fs::remove_file(dump).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_retries_server_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/stations"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/stations"))
+            .and(header("Ocp-Apim-Subscription-Key", "secret"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("{}"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = test_client(NsApiConfig::default());
+        let body = client
+            .get_text(&format!("{}/stations", server.uri()))
+            .await
+            .unwrap();
+
+        assert_eq!(body, "{}");
+    }
+
+    #[tokio::test]
+    async fn it_honours_retry_after() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .mount(&server)
+            .await;
+
+        let client = test_client(NsApiConfig::default());
+        let start = Instant::now();
+        client.get_text(&server.uri()).await.unwrap();
+
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn it_gives_up_after_max_retries() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        let client = test_client(NsApiConfig {
+            max_retries: 2,
+            ..Default::default()
+        });
+
+        assert!(client.get_text(&server.uri()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_does_not_retry_client_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = test_client(NsApiConfig::default());
+
+        assert!(client.get_text(&server.uri()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_stops_when_the_request_budget_is_exhausted() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        let client = test_client(NsApiConfig {
+            request_budget: Some(3),
+            ..Default::default()
+        });
+
+        let error = client.get_text(&server.uri()).await.unwrap_err();
+        assert!(error.to_string().contains("budget"));
+    }
+
+    #[tokio::test]
+    async fn it_gives_every_run_its_own_budget() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let client = test_client(NsApiConfig {
+            request_budget: Some(1),
+            ..Default::default()
+        });
+
+        client.for_run().get_text(&server.uri()).await.unwrap();
+        client.for_run().get_text(&server.uri()).await.unwrap();
+    }
 }
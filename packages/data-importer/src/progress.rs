@@ -0,0 +1,157 @@
+use crate::logging::logger;
+use serde::Serialize;
+use slog::info;
+use std::time::{Duration, Instant};
+
+/// How often progress is logged while importing.
+pub(crate) const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A service (or journey) that could not be imported, even after retrying.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ImportFailure {
+    pub id: String,
+    pub error: String,
+}
+
+/// Outcome of an import run, logged at the end and optionally written to a file as JSON.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ImportSummary {
+    pub total: u64,
+    pub processed: u64,
+    pub skipped: u64,
+    pub failed: u64,
+    pub duration_secs: f64,
+    pub failures: Vec<ImportFailure>,
+}
+
+/// Keeps count of how many of `total` items were handled so far.
+pub(crate) struct Progress {
+    what: &'static str,
+    total: u64,
+    processed: u64,
+    skipped: u64,
+    failed: u64,
+    failures: Vec<ImportFailure>,
+    started: Instant,
+}
+
+impl Progress {
+    pub fn new(what: &'static str, total: usize) -> Self {
+        Progress {
+            what,
+            total: total as u64,
+            processed: 0,
+            skipped: 0,
+            failed: 0,
+            failures: Vec::new(),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn processed(&mut self) {
+        self.processed += 1;
+    }
+
+    pub fn skipped(&mut self) {
+        self.skipped += 1;
+    }
+
+    pub fn failed(&mut self, id: impl ToString, error: &anyhow::Error) {
+        self.failed += 1;
+        self.failures.push(ImportFailure {
+            id: id.to_string(),
+            error: format!("{error:#}"),
+        });
+    }
+
+    fn done(&self) -> u64 {
+        self.processed + self.skipped + self.failed
+    }
+
+    /// Items per second since the start.
+    fn rate(&self) -> f64 {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            self.done() as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    /// Estimated time until all items are handled, if anything was handled yet.
+    fn eta(&self) -> Option<Duration> {
+        let rate = self.rate();
+        (rate > 0.0)
+            .then(|| Duration::from_secs_f64(self.total.saturating_sub(self.done()) as f64 / rate))
+    }
+
+    pub fn log(&self) {
+        info!(logger(), "Import progress";
+            "what" => self.what,
+            "done" => self.done(),
+            "total" => self.total,
+            "processed" => self.processed,
+            "skipped" => self.skipped,
+            "failed" => self.failed,
+            "rate" => format!("{:.1}/s", self.rate()),
+            "eta_secs" => self.eta().map(|eta| eta.as_secs()),
+        );
+    }
+
+    /// Logs and returns the final summary.
+    pub fn finish(self) -> ImportSummary {
+        let summary = ImportSummary {
+            total: self.total,
+            processed: self.processed,
+            skipped: self.skipped,
+            failed: self.failed,
+            duration_secs: self.started.elapsed().as_secs_f64(),
+            failures: self.failures,
+        };
+
+        info!(logger(), "Import finished";
+            "what" => self.what,
+            "total" => summary.total,
+            "processed" => summary.processed,
+            "skipped" => summary.skipped,
+            "failed" => summary.failed,
+            "duration_secs" => format!("{:.1}", summary.duration_secs),
+        );
+
+        summary
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_estimates_remaining_time() {
+        let mut progress = Progress::new("services", 4);
+        assert_eq!(progress.eta(), None);
+
+        progress.started = Instant::now() - Duration::from_secs(10);
+        progress.processed();
+        progress.skipped();
+
+        // 2 done in 10 seconds, so the other 2 should take another 10
+        let eta = progress.eta().unwrap();
+        assert!(eta > Duration::from_secs(9) && eta < Duration::from_secs(11));
+
+        progress.failed(1234, &anyhow::anyhow!("! footnote not found"));
+        let summary = progress.finish();
+        assert_eq!(
+            (summary.processed, summary.skipped, summary.failed),
+            (1, 1, 1)
+        );
+        assert_eq!(summary.total, 4);
+        assert_eq!(
+            summary.failures,
+            vec![ImportFailure {
+                id: "1234".to_string(),
+                error: "! footnote not found".to_string()
+            }]
+        );
+    }
+}
@@ -0,0 +1,447 @@
+use crate::db;
+use crate::importers::timetable::quality::ReportFormat;
+use anyhow::{Result, bail};
+use deadpool_postgres::Pool;
+use sea_query::Iden;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+
+/// A table as the importer uses it. Types are Postgres `udt_name`s, so `_text` for `text[]`.
+#[derive(Debug)]
+struct ExpectedTable {
+    name: String,
+    columns: Vec<(String, &'static str)>,
+    /// Column sets that are used as `ON CONFLICT` target, each needs a unique constraint
+    unique: Vec<BTreeSet<String>>,
+}
+
+fn expected_table<T: Iden>(
+    table: T,
+    columns: Vec<(T, &'static str)>,
+    unique: Vec<Vec<T>>,
+) -> ExpectedTable {
+    ExpectedTable {
+        name: table.to_string(),
+        columns: columns
+            .into_iter()
+            .map(|(column, udt_name)| (column.to_string(), udt_name))
+            .collect(),
+        unique: unique
+            .into_iter()
+            .map(|columns| columns.iter().map(Iden::to_string).collect())
+            .collect(),
+    }
+}
+
+/// With `postgis`, the geometry columns that are only written in PostGIS mode are expected too.
+fn expected_tables(postgis: bool) -> Vec<ExpectedTable> {
+    use db::{
+        ImportState, Journey, JourneyEvent, RouteShape, Service, ServiceTransfer, Station,
+        StationGeometry, StationHistory, StationTransfer,
+    };
+
+    let mut tables = vec![
+        expected_table(
+            Service::Table,
+            vec![
+                (Service::Id, "uuid"),
+                (Service::TrainNumber, "text"),
+                (Service::TimetableYear, "text"),
+                (Service::Type, "text"),
+                (Service::Provider, "text"),
+            ],
+            vec![vec![Service::TrainNumber, Service::TimetableYear]],
+        ),
+        expected_table(
+            Journey::Table,
+            vec![
+                (Journey::Id, "uuid"),
+                (Journey::ServiceId, "uuid"),
+                (Journey::RunningOn, "date"),
+                (Journey::Attributes, "_text"),
+                (Journey::SourceIds, "_text"),
+                (Journey::RouteShapeId, "uuid"),
+                (Journey::DistancePlanned, "int4"),
+            ],
+            vec![vec![Journey::ServiceId, Journey::RunningOn]],
+        ),
+        expected_table(
+            RouteShape::Table,
+            vec![
+                (RouteShape::Id, "uuid"),
+                (RouteShape::StopPattern, "text"),
+                (RouteShape::Stations, "_text"),
+                (RouteShape::Shape, "jsonb"),
+                (RouteShape::Gaps, "jsonb"),
+            ],
+            vec![vec![RouteShape::StopPattern]],
+        ),
+        expected_table(
+            JourneyEvent::Table,
+            vec![
+                (JourneyEvent::Id, "uuid"),
+                (JourneyEvent::JourneyId, "uuid"),
+                (JourneyEvent::Station, "text"),
+                (JourneyEvent::EventTypePlanned, "text"),
+                (JourneyEvent::StopOrder, "int4"),
+                (JourneyEvent::ArrivalTimePlanned, "time"),
+                (JourneyEvent::ArrivalPlatformPlanned, "text"),
+                (JourneyEvent::DepartureTimePlanned, "time"),
+                (JourneyEvent::DeparturePlatformPlanned, "text"),
+                (JourneyEvent::Attributes, "_text"),
+                (JourneyEvent::DistancePlanned, "int4"),
+                (JourneyEvent::SpeedPlanned, "float8"),
+            ],
+            vec![vec![JourneyEvent::JourneyId, JourneyEvent::StopOrder]],
+        ),
+        expected_table(
+            Station::Table,
+            vec![
+                (Station::UicCode, "text"),
+                (Station::UicCdCode, "text"),
+                (Station::EvaCode, "text"),
+                (Station::CdCode, "int4"),
+                (Station::Code, "text"),
+                (Station::StationType, "text"),
+                (Station::NameLong, "text"),
+                (Station::NameMedium, "text"),
+                (Station::NameShort, "text"),
+                (Station::NameSynonyms, "_text"),
+                (Station::Country, "varchar"),
+                (Station::Tracks, "_text"),
+                (Station::HasTravelAssistance, "bool"),
+                (Station::IsBorderStop, "bool"),
+                (Station::IsAvailableForAccessibleTravel, "bool"),
+                (Station::HasKnownFacilities, "bool"),
+                (Station::AreTracksIndependentlyAccessible, "bool"),
+                (Station::Location, "point"),
+                (Station::DeletedAt, "timestamptz"),
+            ],
+            vec![vec![Station::UicCode]],
+        ),
+        expected_table(
+            StationHistory::Table,
+            vec![
+                (StationHistory::UicCode, "text"),
+                (StationHistory::Field, "text"),
+                (StationHistory::OldValue, "text"),
+                (StationHistory::NewValue, "text"),
+            ],
+            vec![],
+        ),
+        expected_table(
+            StationGeometry::Table,
+            vec![
+                (StationGeometry::From, "text"),
+                (StationGeometry::To, "text"),
+                (StationGeometry::LineString, "jsonb"),
+            ],
+            vec![vec![StationGeometry::From, StationGeometry::To]],
+        ),
+        expected_table(
+            StationTransfer::Table,
+            vec![
+                (StationTransfer::StationCode, "text"),
+                (StationTransfer::IsInterchange, "bool"),
+                (StationTransfer::MinimumTransferMinutes, "int4"),
+            ],
+            vec![vec![StationTransfer::StationCode]],
+        ),
+        expected_table(
+            ServiceTransfer::Table,
+            vec![
+                (ServiceTransfer::StationCode, "text"),
+                (ServiceTransfer::FromTrainNumber, "text"),
+                (ServiceTransfer::ToTrainNumber, "text"),
+                (ServiceTransfer::Possibility, "text"),
+                (ServiceTransfer::MinimumTransferMinutes, "int4"),
+            ],
+            vec![vec![
+                ServiceTransfer::StationCode,
+                ServiceTransfer::FromTrainNumber,
+                ServiceTransfer::ToTrainNumber,
+            ]],
+        ),
+        expected_table(
+            ImportState::Table,
+            vec![
+                (ImportState::Task, "text"),
+                (ImportState::Marker, "text"),
+                (ImportState::UpdatedAt, "timestamptz"),
+            ],
+            vec![vec![ImportState::Task]],
+        ),
+    ];
+
+    if postgis {
+        for (table, column) in [
+            (
+                Station::Table.to_string(),
+                Station::LocationGeometry.to_string(),
+            ),
+            (
+                StationGeometry::Table.to_string(),
+                StationGeometry::LineStringGeometry.to_string(),
+            ),
+        ] {
+            if let Some(table) = tables.iter_mut().find(|expected| expected.name == table) {
+                table.columns.push((column, "geometry"));
+            }
+        }
+    }
+
+    tables
+}
+
+/// A table as it is in the database.
+#[derive(Debug, Default)]
+struct ActualTable {
+    /// Column name to `udt_name`
+    columns: BTreeMap<String, String>,
+    /// Columns of every unique and primary key constraint
+    unique: Vec<BTreeSet<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Mismatch {
+    MissingTable {
+        table: String,
+    },
+    MissingColumn {
+        table: String,
+        column: String,
+    },
+    WrongType {
+        table: String,
+        column: String,
+        expected: String,
+        actual: String,
+    },
+    MissingUniqueConstraint {
+        table: String,
+        columns: Vec<String>,
+    },
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mismatch::MissingTable { table } => write!(f, "table {table} does not exist"),
+            Mismatch::MissingColumn { table, column } => {
+                write!(f, "column {table}.{column} does not exist")
+            }
+            Mismatch::WrongType {
+                table,
+                column,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "column {table}.{column} is {actual}, expected {expected}"
+            ),
+            Mismatch::MissingUniqueConstraint { table, columns } => write!(
+                f,
+                "table {table} has no unique constraint on ({})",
+                columns.join(", ")
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SchemaReport {
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl SchemaReport {
+    pub fn to_text(&self) -> String {
+        if self.mismatches.is_empty() {
+            return "schema matches\n".to_string();
+        }
+
+        let mut text = format!("{} mismatches\n", self.mismatches.len());
+        for mismatch in &self.mismatches {
+            text.push_str(&format!("  {mismatch}\n"));
+        }
+
+        text
+    }
+}
+
+fn compare(expected: &[ExpectedTable], actual: &BTreeMap<String, ActualTable>) -> SchemaReport {
+    let mut mismatches = Vec::new();
+    for table in expected {
+        let Some(actual) = actual.get(&table.name) else {
+            mismatches.push(Mismatch::MissingTable {
+                table: table.name.clone(),
+            });
+            continue;
+        };
+
+        for (column, expected_type) in &table.columns {
+            match actual.columns.get(column) {
+                None => mismatches.push(Mismatch::MissingColumn {
+                    table: table.name.clone(),
+                    column: column.clone(),
+                }),
+                Some(actual_type) if actual_type != expected_type => {
+                    mismatches.push(Mismatch::WrongType {
+                        table: table.name.clone(),
+                        column: column.clone(),
+                        expected: String::from(*expected_type),
+                        actual: actual_type.clone(),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+
+        // ON CONFLICT needs a constraint on exactly these columns, in any order
+        for columns in &table.unique {
+            if !actual.unique.contains(columns) {
+                mismatches.push(Mismatch::MissingUniqueConstraint {
+                    table: table.name.clone(),
+                    columns: columns.iter().cloned().collect(),
+                });
+            }
+        }
+    }
+
+    mismatches.sort();
+    SchemaReport { mismatches }
+}
+
+async fn load_tables(db: &Pool, names: &[String]) -> Result<BTreeMap<String, ActualTable>> {
+    let client = db.get().await?;
+    let mut tables: BTreeMap<String, ActualTable> = BTreeMap::new();
+
+    let columns = client
+        .query(
+            "SELECT table_name::text, column_name::text, udt_name::text
+            FROM information_schema.columns
+            WHERE table_schema = current_schema() AND table_name = ANY($1)",
+            &[&names],
+        )
+        .await?;
+    for row in columns {
+        tables
+            .entry(row.get(0))
+            .or_default()
+            .columns
+            .insert(row.get(1), row.get(2));
+    }
+
+    let constraints = client
+        .query(
+            "SELECT tc.table_name::text, tc.constraint_name::text, kcu.column_name::text
+            FROM information_schema.table_constraints tc
+            JOIN information_schema.key_column_usage kcu
+                ON kcu.constraint_schema = tc.constraint_schema
+                AND kcu.constraint_name = tc.constraint_name
+                AND kcu.table_name = tc.table_name
+            WHERE tc.table_schema = current_schema()
+                AND tc.table_name = ANY($1)
+                AND tc.constraint_type IN ('UNIQUE', 'PRIMARY KEY')",
+            &[&names],
+        )
+        .await?;
+    let mut unique: BTreeMap<(String, String), BTreeSet<String>> = BTreeMap::new();
+    for row in constraints {
+        unique
+            .entry((row.get(0), row.get(1)))
+            .or_default()
+            .insert(row.get(2));
+    }
+    for ((table, _), columns) in unique {
+        tables.entry(table).or_default().unique.push(columns);
+    }
+
+    Ok(tables)
+}
+
+/// Compares the tables in the database with what the importer expects, so a migration that
+/// renamed or changed something shows up before anything is written instead of halfway through
+/// an import. Fails if anything does not match.
+pub async fn check(db: &Pool, format: ReportFormat, postgis: bool) -> Result<()> {
+    let expected = expected_tables(postgis);
+    let names: Vec<String> = expected.iter().map(|table| table.name.clone()).collect();
+
+    let report = compare(&expected, &load_tables(db, &names).await?);
+    match format {
+        ReportFormat::Text => print!("{}", report.to_text()),
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+
+    if !report.mismatches.is_empty() {
+        bail!(
+            "! database schema does not match, found {} mismatches",
+            report.mismatches.len()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn actual_table(table: &ExpectedTable) -> ActualTable {
+        ActualTable {
+            columns: table
+                .columns
+                .iter()
+                .map(|(column, udt_name)| (column.clone(), String::from(*udt_name)))
+                .collect(),
+            unique: table.unique.clone(),
+        }
+    }
+
+    #[test]
+    fn it_reports_mismatches() {
+        let expected = expected_tables(false);
+        let mut actual: BTreeMap<String, ActualTable> = expected
+            .iter()
+            .map(|table| (table.name.clone(), actual_table(table)))
+            .collect();
+        assert!(compare(&expected, &actual).mismatches.is_empty());
+
+        actual.remove("station_history");
+        let journey_event = actual.get_mut("journey_event").unwrap();
+        journey_event.columns.remove("event_type_planned");
+        journey_event
+            .columns
+            .insert("stop_order".to_string(), "text".to_string());
+        // order of the columns doesn't matter for ON CONFLICT
+        actual.get_mut("service").unwrap().unique = vec![BTreeSet::from([
+            "timetable_year".to_string(),
+            "train_number".to_string(),
+        ])];
+        actual.get_mut("station_geometry").unwrap().unique.clear();
+
+        assert_eq!(
+            compare(&expected, &actual).mismatches,
+            vec![
+                Mismatch::MissingTable {
+                    table: "station_history".to_string()
+                },
+                Mismatch::MissingColumn {
+                    table: "journey_event".to_string(),
+                    column: "event_type_planned".to_string()
+                },
+                Mismatch::WrongType {
+                    table: "journey_event".to_string(),
+                    column: "stop_order".to_string(),
+                    expected: "int4".to_string(),
+                    actual: "text".to_string()
+                },
+                Mismatch::MissingUniqueConstraint {
+                    table: "station_geometry".to_string(),
+                    columns: vec!["from".to_string(), "to".to_string()]
+                },
+            ]
+        );
+    }
+}
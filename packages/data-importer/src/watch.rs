@@ -0,0 +1,285 @@
+use crate::db;
+use crate::importers::timetable::cache::DeliveryCache;
+use crate::importers::timetable::{DATA_URL, WorkerOptions, load_delivery, process_delivery};
+use crate::importers::{station_geometry, stations};
+use crate::instrumentation::in_span;
+use crate::logging::logger;
+use crate::ns::{ResponseSource, load_response};
+use anyhow::{Context, Result};
+use deadpool_postgres::{Object, Pool};
+use opentelemetry::KeyValue;
+use sea_query::{Expr, OnConflict, PostgresQueryBuilder, Query};
+use sea_query_postgres::PostgresBinder;
+use slog::{error, info};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{Interval, MissedTickBehavior, interval};
+
+/// Key of the advisory lock that is held while importing, shared by all importers so only one
+/// replica imports anything at a time.
+const IMPORT_LOCK_KEY: i64 = 0x6b6564656e67;
+
+/// A session-level advisory lock, held by keeping the connection that took it out of the pool
+/// until it is released. If the process dies, Postgres releases the lock with the connection.
+struct ImportLock {
+    client: Object,
+}
+
+impl ImportLock {
+    async fn try_acquire(db: &Pool) -> Result<Option<Self>> {
+        let client = db.get().await?;
+        let acquired: bool = client
+            .query_one("SELECT pg_try_advisory_lock($1)", &[&IMPORT_LOCK_KEY])
+            .await
+            .context("! failed to take import lock")?
+            .get(0);
+
+        Ok(acquired.then_some(ImportLock { client }))
+    }
+
+    /// If unlocking fails, the connection is closed instead of going back to the pool, which
+    /// releases the lock as well.
+    async fn release(self) {
+        if let Err(e) = self
+            .client
+            .execute("SELECT pg_advisory_unlock($1)", &[&IMPORT_LOCK_KEY])
+            .await
+        {
+            error!(logger(), "Failed to release import lock, closing its connection"; "error" => e.to_string());
+            drop(Object::take(self.client));
+        }
+    }
+}
+
+pub struct WatchOptions {
+    pub timetable_interval: Duration,
+    pub stations_interval: Duration,
+    pub station_geometry_interval: Duration,
+    pub cache: DeliveryCache,
+    pub ns_api: ResponseSource,
+    pub workers: WorkerOptions,
+    /// Also write station locations and geometry to their PostGIS columns
+    pub postgis: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Task {
+    Timetable,
+    Stations,
+    StationGeometry,
+}
+
+impl Display for Task {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            Task::Timetable => "timetable",
+            Task::Stations => "stations",
+            Task::StationGeometry => "station geometry",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+impl Task {
+    fn key(&self) -> &'static str {
+        match self {
+            Task::Timetable => "timetable",
+            Task::Stations => "stations",
+            Task::StationGeometry => "station_geometry",
+        }
+    }
+}
+
+/// FNV-1a, as the hash is stored and has to stay the same across builds.
+fn hash_response(response: &str) -> String {
+    let hash = response.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
+}
+
+/// Whether there is something to import. Without a marker, such as a delivery without a version,
+/// there is no telling, so it is imported again.
+fn has_changed(stored: Option<&str>, current: Option<&str>) -> bool {
+    current.is_none() || stored != current
+}
+
+/// The marker of what was imported last for `task`, by any replica.
+async fn stored_marker(client: &Object, task: Task) -> Result<Option<String>> {
+    let (sql, params) = Query::select()
+        .column(db::ImportState::Marker)
+        .from(db::ImportState::Table)
+        .and_where(Expr::col(db::ImportState::Task).eq(task.key()))
+        .build_postgres(PostgresQueryBuilder);
+
+    let row = client
+        .query_opt(sql.as_str(), &params.as_params())
+        .await
+        .context("! failed to load import state")?;
+
+    Ok(row.map(|row| row.get(0)))
+}
+
+async fn store_marker(client: &Object, task: Task, marker: &str) -> Result<()> {
+    let (sql, params) = Query::insert()
+        .into_table(db::ImportState::Table)
+        .columns([
+            db::ImportState::Task,
+            db::ImportState::Marker,
+            db::ImportState::UpdatedAt,
+        ])
+        .values_panic([
+            task.key().into(),
+            marker.into(),
+            Expr::current_timestamp().into(),
+        ])
+        .on_conflict(
+            OnConflict::column(db::ImportState::Task)
+                .update_columns([db::ImportState::Marker, db::ImportState::UpdatedAt])
+                .to_owned(),
+        )
+        .build_postgres(PostgresQueryBuilder);
+
+    client
+        .execute(sql.as_str(), &params.as_params())
+        .await
+        .context("! failed to store import state")?;
+
+    Ok(())
+}
+
+fn schedule(period: Duration) -> Interval {
+    let mut interval = interval(period);
+    // an import can easily take longer than the interval, don't try to catch up after that
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+/// Imports the data of `task` if it changed since the last import. The state is read and written
+/// with the connection holding the lock, so replicas never import the same data twice.
+async fn run_task(
+    db: &Arc<Pool>,
+    lock: &ImportLock,
+    options: &WatchOptions,
+    task: Task,
+) -> Result<()> {
+    let stored = stored_marker(&lock.client, task).await?;
+
+    let marker = match task {
+        Task::Timetable => {
+            let delivery_dir = options.cache.fetch(DATA_URL).await?;
+            let version = delivery_dir
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string());
+
+            if !has_changed(stored.as_deref(), version.as_deref()) {
+                info!(logger(), "Delivery was already imported"; "version" => version);
+                return Ok(());
+            }
+
+            let delivery = load_delivery(&delivery_dir)?;
+            process_delivery(Arc::clone(db), &delivery, None, options.workers).await?;
+            version
+        }
+        Task::Stations => {
+            let response =
+                load_response(stations::API_URL, &options.ns_api.for_run(), None).await?;
+            let hash = Some(hash_response(&response));
+            if !has_changed(stored.as_deref(), hash.as_deref()) {
+                info!(logger(), "Stations did not change");
+                return Ok(());
+            }
+
+            stations::import_response(Arc::clone(db), &response, options.postgis).await?;
+            hash
+        }
+        Task::StationGeometry => {
+            let response =
+                load_response(station_geometry::API_URL, &options.ns_api.for_run(), None).await?;
+            let hash = Some(hash_response(&response));
+            if !has_changed(stored.as_deref(), hash.as_deref()) {
+                info!(logger(), "Station geometry did not change");
+                return Ok(());
+            }
+
+            station_geometry::import_response(Arc::clone(db), &response, options.postgis).await?;
+            hash
+        }
+    };
+
+    if let Some(marker) = marker {
+        store_marker(&lock.client, task, &marker).await?;
+    }
+
+    Ok(())
+}
+
+/// Keeps running and checks for new timetable, station and station geometry data on their own
+/// schedules, importing whatever changed. Imports are guarded by an advisory lock, so running
+/// multiple replicas is safe: a replica that can't get the lock skips its turn.
+pub async fn watch(db: Arc<Pool>, options: WatchOptions) -> Result<()> {
+    let mut stations = schedule(options.stations_interval);
+    let mut station_geometry = schedule(options.station_geometry_interval);
+    let mut timetable = schedule(options.timetable_interval);
+
+    loop {
+        // all schedules fire right away on startup, stations go first as the others refer to them
+        let task = tokio::select! {
+            biased;
+            _ = stations.tick() => Task::Stations,
+            _ = station_geometry.tick() => Task::StationGeometry,
+            _ = timetable.tick() => Task::Timetable,
+        };
+
+        let lock = match ImportLock::try_acquire(&db).await {
+            Ok(Some(lock)) => lock,
+            Ok(None) => {
+                info!(logger(), "Another importer holds the lock, skipping"; "task" => task.to_string());
+                continue;
+            }
+            Err(e) => {
+                error!(logger(), "Failed to take import lock, skipping"; "task" => task.to_string(), "error" => format!("{e:#}"));
+                continue;
+            }
+        };
+
+        info!(logger(), "Checking for changes"; "task" => task.to_string());
+        let attributes = vec![KeyValue::new("task", task.to_string())];
+        let result = in_span(
+            "watch_task",
+            attributes,
+            run_task(&db, &lock, &options, task),
+        )
+        .await;
+        if let Err(e) = result {
+            error!(logger(), "Failed to import"; "task" => task.to_string(), "error" => format!("{e:#}"));
+        }
+
+        lock.release().await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_detects_changes() {
+        assert!(has_changed(None, Some("20250601")));
+        assert!(has_changed(Some("20250601"), Some("20250602")));
+        assert!(!has_changed(Some("20250601"), Some("20250601")));
+        // without a version there is no telling whether it changed
+        assert!(has_changed(Some("20250601"), None));
+    }
+
+    #[test]
+    fn it_hashes_responses_stably() {
+        assert_eq!(hash_response(""), "cbf29ce484222325");
+        assert_eq!(hash_response("a"), "af63dc4c8601ec8c");
+        assert_ne!(
+            hash_response("{\"payload\": []}"),
+            hash_response("{\"payload\": [1]}")
+        );
+    }
+}
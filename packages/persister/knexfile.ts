@@ -24,6 +24,12 @@ const config: Knex.Config = {
     tableName: "_migrations",
     loadExtensions: process.env.NODE_ENV === "production" ? [".js"] : undefined,
   },
+
+  seeds: {
+    extension: "ts",
+    directory: "seeds",
+    loadExtensions: process.env.NODE_ENV === "production" ? [".js"] : undefined,
+  },
 };
 
 export default config;
@@ -0,0 +1,38 @@
+import type { Knex } from "knex";
+
+export async function up(knex: Knex): Promise<void> {
+  await knex.schema.alterTable("station", (table) => {
+    table.timestamp("deleted_at", { useTz: true }).nullable();
+  });
+
+  await knex.schema.createTable("station_history", (table) => {
+    table
+      .uuid("id")
+      .primary()
+      .defaultTo(knex.raw("gen_random_uuid()"))
+      .notNullable();
+
+    table.text("uic_code").notNullable().index();
+    table
+      .foreign("uic_code")
+      .references("station.uic_code")
+      .onDelete("CASCADE");
+
+    table.text("field").notNullable();
+    table.text("old_value");
+    table.text("new_value");
+
+    table
+      .timestamp("changed_at", { useTz: true })
+      .notNullable()
+      .defaultTo(knex.fn.now());
+  });
+}
+
+export async function down(knex: Knex): Promise<void> {
+  await knex.schema.dropTable("station_history");
+
+  await knex.schema.alterTable("station", (table) => {
+    table.dropColumn("deleted_at");
+  });
+}
@@ -0,0 +1,36 @@
+import type { Knex } from "knex";
+
+export async function up(knex: Knex): Promise<void> {
+  await knex.schema.createTable("route_shape", (table) => {
+    table
+      .uuid("id")
+      .primary()
+      .defaultTo(knex.raw("gen_random_uuid()"))
+      .notNullable();
+
+    // station codes the journey passes, passages included, separated by commas
+    table.text("stop_pattern").notNullable().unique();
+    table.specificType("stations", "text[]").notNullable();
+
+    // GeoJSON MultiLineString, split wherever there is no track geometry between two stations
+    table.jsonb("shape").notNullable();
+    table.jsonb("gaps").notNullable();
+  });
+
+  await knex.schema.alterTable("journey", (table) => {
+    table.uuid("route_shape_id").nullable();
+    table
+      .foreign("route_shape_id")
+      .references("route_shape.id")
+      .onDelete("SET NULL");
+  });
+}
+
+export async function down(knex: Knex): Promise<void> {
+  await knex.schema.alterTable("journey", (table) => {
+    table.dropForeign(["route_shape_id"]);
+    table.dropColumn("route_shape_id");
+  });
+
+  await knex.schema.dropTable("route_shape");
+}
@@ -0,0 +1,26 @@
+import type { Knex } from "knex";
+
+export async function up(knex: Knex): Promise<void> {
+  await knex.schema.alterTable("journey", (table) => {
+    // metres along the track, null if part of the route has no geometry
+    table.integer("distance_planned").nullable();
+  });
+
+  await knex.schema.alterTable("journey_event", (table) => {
+    // metres along the track from the previous event
+    table.integer("distance_planned").nullable();
+    // average km/h since departing from the previous stop
+    table.double("speed_planned").nullable();
+  });
+}
+
+export async function down(knex: Knex): Promise<void> {
+  await knex.schema.alterTable("journey_event", (table) => {
+    table.dropColumn("speed_planned");
+    table.dropColumn("distance_planned");
+  });
+
+  await knex.schema.alterTable("journey", (table) => {
+    table.dropColumn("distance_planned");
+  });
+}
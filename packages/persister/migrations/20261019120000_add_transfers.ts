@@ -0,0 +1,30 @@
+import type { Knex } from "knex";
+
+export async function up(knex: Knex): Promise<void> {
+  await knex.schema.createTable("station_transfer", (table) => {
+    // lowercase IFF station code
+    table.text("station_code").primary().notNullable();
+    // changing trains is only planned at interchange stations
+    table.boolean("is_interchange").notNullable();
+    table.integer("minimum_transfer_minutes").notNullable();
+  });
+
+  // service pairs from changes.dat, which take precedence over station_transfer
+  await knex.schema.createTable("service_transfer", (table) => {
+    table.text("station_code").notNullable();
+    table.text("from_train_number").notNullable();
+    table.text("to_train_number").notNullable();
+    table
+      .enum("possibility", ["not_possible", "possible", "guaranteed"])
+      .notNullable();
+    // null if changing between the two is not possible
+    table.integer("minimum_transfer_minutes").nullable();
+
+    table.unique(["station_code", "from_train_number", "to_train_number"]);
+  });
+}
+
+export async function down(knex: Knex): Promise<void> {
+  await knex.schema.dropTable("service_transfer");
+  await knex.schema.dropTable("station_transfer");
+}
@@ -0,0 +1,20 @@
+import type { Knex } from "knex";
+
+export async function up(knex: Knex): Promise<void> {
+  // what the watcher imported last, so a restarted or other replica doesn't import it again
+  await knex.schema.createTable("import_state", (table) => {
+    // timetable, stations or station_geometry
+    table.text("task").primary().notNullable();
+    // delivery version or hash of the API response
+    table.text("marker").notNullable();
+
+    table
+      .timestamp("updated_at", { useTz: true })
+      .notNullable()
+      .defaultTo(knex.fn.now());
+  });
+}
+
+export async function down(knex: Knex): Promise<void> {
+  await knex.schema.dropTable("import_state");
+}
@@ -0,0 +1,26 @@
+import type { Knex } from "knex";
+
+// geometry columns next to station.location and station_geometry.line_string, which are still
+// read by the API, so PostGIS stays optional for anyone running without it. This is a seed rather
+// than a migration, as seeds run every time: turning on DB_POSTGIS later still adds the columns.
+export async function seed(knex: Knex): Promise<void> {
+  if (process.env.DB_POSTGIS !== "true") {
+    return;
+  }
+
+  // fails if PostGIS is not available on the server, rather than silently leaving out the columns
+  await knex.raw("CREATE EXTENSION IF NOT EXISTS postgis");
+
+  await knex.raw(
+    'ALTER TABLE "station" ADD COLUMN IF NOT EXISTS "location_geometry" geometry(Point, 4326)',
+  );
+  await knex.raw(
+    'CREATE INDEX IF NOT EXISTS "station_location_geometry_index" ON "station" USING gist ("location_geometry")',
+  );
+  await knex.raw(
+    'ALTER TABLE "station_geometry" ADD COLUMN IF NOT EXISTS "line_string_geometry" geometry(LineString, 4326)',
+  );
+  await knex.raw(
+    'CREATE INDEX IF NOT EXISTS "station_geometry_line_string_geometry_index" ON "station_geometry" USING gist ("line_string_geometry")',
+  );
+}